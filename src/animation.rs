@@ -0,0 +1,341 @@
+use std::{collections::HashMap, fs::File, io::BufReader, num::NonZeroUsize, path::Path, rc::Rc, thread, time::{Duration, Instant}};
+
+use anyhow::{Context, Result, anyhow};
+use image::{AnimationDecoder, DynamicImage, Frames, ImageFormat, RgbaImage, codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder}};
+
+use crate::{color::Fill, render::{ScaleMode, is_opaque, scale_image}};
+
+/// Frames of an animated image and how long each one is shown.
+pub struct Animation {
+    pub frames: Vec<RgbaImage>,
+    pub delays: Vec<Duration>,
+    /// No frame has transparent pixels
+    pub opaque: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnimationSettings {
+    /// How often an animation plays, 0 for endless
+    pub loops: u32,
+    pub max_fps: u32,
+}
+
+impl Default for AnimationSettings {
+    fn default() -> Self {
+        AnimationSettings { loops: 0, max_fps: 30 }
+    }
+}
+
+/// What a file turned out to be once it was opened.
+pub enum Decoded<A> {
+    Animated(A),
+    /// A still image, with its pixels if they were decoded while looking for frames
+    Still(Option<DynamicImage>),
+}
+
+/// Decodes every frame of a GIF, APNG or animated WebP.
+/// Still images are left for `image::open`, unless their only frame was already decoded.
+pub fn decode_animation(path: &str) -> Result<Decoded<Animation>> {
+    let format = image::io::Reader::open(path)?.with_guessed_format()?.format();
+    let reader = || -> Result<BufReader<File>> {
+        Ok(BufReader::new(File::open(path).with_context(|| format!("Failed to open {path}"))?))
+    };
+
+    let frames: Frames = match format {
+        Some(ImageFormat::Gif) => GifDecoder::new(reader()?)?.into_frames(),
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(reader()?)?;
+            if !decoder.is_apng() {
+                return Ok(Decoded::Still(None));
+            }
+            decoder.apng().into_frames()
+        }
+        Some(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader()?)?;
+            if !decoder.has_animation() {
+                return Ok(Decoded::Still(None));
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(Decoded::Still(None)),
+    };
+
+    let mut animation = Animation { frames: Vec::new(), delays: Vec::new(), opaque: false };
+    for frame in frames {
+        let frame = frame.with_context(|| format!("Failed to decode a frame of {path}"))?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay = Duration::from_millis((numer / denom.max(1)) as u64);
+        // Browsers slow down frames of 10ms and less the same way
+        animation.delays.push(if delay <= Duration::from_millis(10) { Duration::from_millis(100) } else { delay });
+        animation.frames.push(frame.into_buffer());
+    }
+    if animation.frames.len() < 2 {
+        return Ok(Decoded::Still(animation.frames.pop().map(DynamicImage::ImageRgba8)));
+    }
+    animation.opaque = animation.frames.iter().all(is_opaque);
+    Ok(Decoded::Animated(animation))
+}
+
+type ScaledKey = (String, u32, u32, ScaleMode, String);
+
+/// Decoded animations and their frames scaled for each output size, shared between outputs.
+#[derive(Default)]
+pub struct AnimationCache {
+    decoded: HashMap<String, Rc<Animation>>,
+    scaled: HashMap<ScaledKey, Rc<Animation>>,
+}
+
+impl AnimationCache {
+    /// Opens `path`, decoding it only once however many outputs show it.
+    pub fn open(&mut self, path: &str) -> Result<Decoded<Rc<Animation>>> {
+        self.prune();
+
+        if let Some(decoded) = self.decoded.get(path) {
+            return Ok(Decoded::Animated(decoded.clone()));
+        }
+        if !Path::new(path).extension().and_then(|e| e.to_str())
+            .is_some_and(|e| ["gif", "png", "apng", "webp"].contains(&e.to_ascii_lowercase().as_str()))
+        {
+            return Ok(Decoded::Still(None));
+        }
+        let animation = match decode_animation(path)? {
+            Decoded::Animated(animation) => animation,
+            Decoded::Still(image) => return Ok(Decoded::Still(image)),
+        };
+        println!("Decoded {} frames of {path}", animation.frames.len());
+        let animation = Rc::new(animation);
+        self.decoded.insert(path.to_string(), animation.clone());
+        Ok(Decoded::Animated(animation))
+    }
+
+    /// Frames of `decoded`, opened from `path`, scaled to `width`x`height`.
+    pub fn scaled(&mut self, path: &str, decoded: &Animation, width: u32, height: u32, mode: ScaleMode, background: &Fill) -> Result<Rc<Animation>> {
+        let key = (path.to_string(), width, height, mode, background.to_string());
+        if let Some(scaled) = self.scaled.get(&key) {
+            return Ok(scaled.clone());
+        }
+
+        // Long animations take a while to scale, so the frames are split between threads
+        let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let chunk_size = decoded.frames.len().div_ceil(threads);
+        let frames: Vec<RgbaImage> = thread::scope(|scope| {
+            let handles: Vec<_> = decoded.frames.chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || {
+                    chunk.iter()
+                        .map(|frame| scale_image(&DynamicImage::ImageRgba8(frame.clone()), width, height, mode, background))
+                        .collect::<Vec<_>>()
+                }))
+                .collect();
+            handles.into_iter()
+                .map(|handle| handle.join().map_err(|_| anyhow!("Failed to scale the frames of {path}")))
+                .collect::<Result<Vec<_>>>()
+        })?.into_iter().flatten().collect();
+        let scaled = Rc::new(Animation {
+            opaque: frames.iter().all(is_opaque),
+            frames,
+            delays: decoded.delays.clone(),
+        });
+        self.scaled.insert(key, scaled.clone());
+        Ok(scaled)
+    }
+
+    /// Forgets animations no output is playing anymore.
+    fn prune(&mut self) {
+        self.scaled.retain(|_, scaled| Rc::strong_count(scaled) > 1);
+        let scaled = &self.scaled;
+        self.decoded.retain(|path, _| scaled.keys().any(|(p, ..)| p == path));
+    }
+}
+
+/// An animation playing on one output.
+pub struct Playback {
+    pub animation: Rc<Animation>,
+    pub index: usize,
+    /// Loops left after the current one, `None` for endless
+    loops_left: Option<u32>,
+    /// When the current frame's time began, which can be before it was shown
+    frame_start: Instant,
+    /// When the next frame is due, `None` once the animation has ended
+    pub next_frame: Option<Instant>,
+    /// The last frame was committed and its frame callback hasn't arrived yet
+    pub waiting: bool,
+    min_delay: Duration,
+}
+
+impl Playback {
+    /// Starts at the first frame, which the caller shows.
+    pub fn new(animation: Rc<Animation>, settings: AnimationSettings) -> Self {
+        let now = Instant::now();
+        let mut playback = Playback {
+            animation,
+            index: 0,
+            loops_left: settings.loops.checked_sub(1),
+            frame_start: now,
+            next_frame: None,
+            waiting: false,
+            min_delay: Duration::from_secs(1) / settings.max_fps.max(1),
+        };
+        playback.schedule(now);
+        playback
+    }
+
+    pub fn current(&self) -> &RgbaImage {
+        &self.animation.frames[self.index]
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        !self.waiting && self.next_frame.is_some_and(|t| now >= t)
+    }
+
+    /// Moves to the frame whose time it is at `now`, skipping frames whose time passed while
+    /// the previous one was on screen. That way animations faster than max_fps, or held up by
+    /// the compositor, keep their real speed. Returns false if there is no new frame to show.
+    pub fn advance(&mut self, now: Instant) -> bool {
+        let mut moved = false;
+        for _ in 0..self.animation.frames.len() {
+            if !self.step() {
+                // The last loop has ended
+                self.next_frame = None;
+                return moved;
+            }
+            moved = true;
+            if self.frame_end() > now {
+                break;
+            }
+        }
+        if self.frame_end() <= now {
+            // More than a whole loop behind, e.g. after a suspend, so it goes on from here
+            self.frame_start = now;
+        }
+        self.schedule(now);
+        true
+    }
+
+    /// Moves on by one frame. Returns false when the last loop has ended.
+    fn step(&mut self) -> bool {
+        let mut next = self.index + 1;
+        if next == self.animation.frames.len() {
+            match self.loops_left {
+                Some(0) => return false,
+                Some(ref mut loops) => *loops -= 1,
+                None => {}
+            }
+            next = 0;
+        }
+        self.frame_start += self.animation.delays[self.index];
+        self.index = next;
+        true
+    }
+
+    fn frame_end(&self) -> Instant {
+        self.frame_start + self.animation.delays[self.index]
+    }
+
+    /// The next frame is due when the current one's time is up, but no sooner than max_fps allows.
+    fn schedule(&mut self, now: Instant) {
+        self.next_frame = Some(self.frame_end().max(now + self.min_delay));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_millis(20);
+
+    /// Four frames of 20ms, told apart by their red value.
+    fn playback(loops: u32, max_fps: u32) -> (Playback, Instant) {
+        let animation = Animation {
+            frames: (0..4).map(|i| RgbaImage::from_pixel(1, 1, image::Rgba([i, 0, 0, 255]))).collect(),
+            delays: vec![DELAY; 4],
+            opaque: true,
+        };
+        let playback = Playback::new(Rc::new(animation), AnimationSettings { loops, max_fps });
+        let start = playback.frame_start;
+        (playback, start)
+    }
+
+    #[test]
+    fn plays_each_frame_in_turn() {
+        let (mut playback, start) = playback(0, 1000);
+        assert_eq!(playback.current()[(0, 0)][0], 0);
+        assert_eq!(playback.next_frame, Some(start + DELAY));
+        for (i, expected) in [1, 2, 3, 0, 1].into_iter().enumerate() {
+            let now = start + DELAY * (i as u32 + 1);
+            assert!(playback.is_due(now));
+            assert!(playback.advance(now));
+            assert_eq!(playback.index, expected);
+            assert_eq!(playback.next_frame, Some(now + DELAY));
+        }
+    }
+
+    #[test]
+    fn waits_for_the_frame_callback() {
+        let (mut playback, start) = playback(0, 1000);
+        playback.waiting = true;
+        assert!(!playback.is_due(start + DELAY));
+        playback.waiting = false;
+        assert!(!playback.is_due(start + DELAY / 2));
+        assert!(playback.is_due(start + DELAY));
+    }
+
+    #[test]
+    fn stops_after_the_loop_limit() {
+        let (mut playback, start) = playback(2, 1000);
+        let mut shown = Vec::new();
+        let mut now = start;
+        while playback.next_frame.is_some() {
+            now += DELAY;
+            if playback.advance(now) {
+                shown.push(playback.index);
+            }
+        }
+        assert_eq!(shown, [1, 2, 3, 0, 1, 2, 3]);
+        assert!(!playback.is_due(now + DELAY * 10));
+    }
+
+    #[test]
+    fn shows_the_last_frame_when_the_end_was_missed() {
+        let (mut playback, start) = playback(1, 1000);
+        assert!(playback.advance(start + Duration::from_secs(1)));
+        assert_eq!(playback.index, 3);
+        assert_eq!(playback.next_frame, None);
+        assert!(!playback.advance(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn loops_forever_when_loops_is_zero() {
+        let (mut playback, start) = playback(0, 1000);
+        for i in 1..=400 {
+            assert!(playback.advance(start + DELAY * i));
+        }
+        assert_eq!(playback.index, 0);
+        assert!(playback.next_frame.is_some());
+    }
+
+    #[test]
+    fn skips_frames_to_keep_up_with_the_fps_cap() {
+        // 10 fps allows a frame every 100ms, the animation changes every 20ms
+        let (mut playback, start) = playback(0, 10);
+        assert_eq!(playback.next_frame, Some(start + Duration::from_millis(100)));
+
+        // At 90ms the fifth frame of the timeline is due, frames 1 to 3 are skipped
+        let now = start + Duration::from_millis(90);
+        assert!(playback.advance(now));
+        assert_eq!(playback.index, 0);
+        assert_eq!(playback.next_frame, Some(now + Duration::from_millis(100)));
+
+        // The timeline doesn't drift: at 130ms the frame from 120ms on is shown
+        assert!(playback.advance(start + Duration::from_millis(130)));
+        assert_eq!(playback.index, 2);
+    }
+
+    #[test]
+    fn starts_over_when_more_than_a_loop_behind() {
+        let (mut playback, start) = playback(0, 1000);
+        let now = start + Duration::from_secs(60);
+        assert!(playback.advance(now));
+        assert_eq!(playback.index, 0);
+        assert_eq!(playback.next_frame, Some(now + DELAY));
+    }
+}
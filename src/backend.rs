@@ -0,0 +1,165 @@
+use anyhow::{Context, Result, anyhow};
+
+use crate::{config::GeneratorConfig, gai::GeminiGenerator, lai::LocalGenerator};
+
+/// What to generate.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationRequest {
+    pub prompt: String,
+    /// Pixel size the image is shown at, backends get as close to it as they can
+    pub size: Option<(u32, u32)>,
+    pub seed: Option<u64>,
+    /// What the image shouldn't show
+    pub negative_prompt: Option<String>,
+}
+
+/// An encoded image file as a backend returned it.
+pub struct GeneratedImage {
+    pub bytes: Vec<u8>,
+    /// File extension matching the encoding, e.g. "png"
+    pub extension: &'static str,
+    pub metadata: ImageMetadata,
+}
+
+#[derive(Debug, Default)]
+pub struct ImageMetadata {
+    /// Name of the backend in `BACKENDS`
+    pub backend: &'static str,
+    pub model: Option<String>,
+    pub seed: Option<u64>,
+}
+
+/// A service or program that turns a prompt into an image.
+pub trait ImageGenerator {
+    fn generate(&self, request: &GenerationRequest) -> Result<GeneratedImage>;
+}
+
+type Factory = fn(&GeneratorConfig) -> Result<Box<dyn ImageGenerator>>;
+
+/// Backends by the name used in `[generator] backend`. A new provider implements
+/// `ImageGenerator` and adds itself here.
+pub const BACKENDS: &[(&str, Factory)] = &[
+    ("gemini", |config| Ok(Box::new(GeminiGenerator::new(config)?))),
+    ("local", |_| Ok(Box::new(LocalGenerator))),
+];
+
+pub fn backend_names() -> Vec<&'static str> {
+    BACKENDS.iter().map(|(name, _)| *name).collect()
+}
+
+/// Tries the configured backends in order until one of them returns an image.
+pub fn generate(request: &GenerationRequest, config: &GeneratorConfig) -> Result<GeneratedImage> {
+    generate_with(BACKENDS, request, config)
+}
+
+/// `generate` looking the configured names up in `backends`.
+fn generate_with(backends: &[(&'static str, Factory)], request: &GenerationRequest, config: &GeneratorConfig) -> Result<GeneratedImage> {
+    let mut errors = Vec::new();
+    for name in &config.backends {
+        let Some(&(name, factory)) = backends.iter().find(|(n, _)| n == name) else {
+            errors.push(format!("{name}: unknown backend"));
+            continue;
+        };
+        match factory(config).and_then(|backend| backend.generate(request)) {
+            Ok(mut image) => {
+                image.metadata.backend = name;
+                return Ok(image);
+            }
+            Err(e) => {
+                eprintln!("The {name} backend failed: {e:#}");
+                errors.push(format!("{name}: {e:#}"));
+            }
+        }
+    }
+    match errors.len() {
+        0 => Err(anyhow!("no generator backend is configured")),
+        1 => Err(anyhow!(errors.remove(0))),
+        _ => Err(anyhow!(errors.join("; "))).context("every backend failed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// Backends asked for an image, in order
+        static TRIED: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct Stub {
+        name: &'static str,
+        works: bool,
+    }
+
+    impl ImageGenerator for Stub {
+        fn generate(&self, _: &GenerationRequest) -> Result<GeneratedImage> {
+            TRIED.with_borrow_mut(|tried| tried.push(self.name));
+            if !self.works {
+                anyhow::bail!("{} is down", self.name);
+            }
+            Ok(GeneratedImage { bytes: self.name.as_bytes().to_vec(), extension: "png", metadata: ImageMetadata::default() })
+        }
+    }
+
+    const STUBS: &[(&str, Factory)] = &[
+        ("broken", |_| Ok(Box::new(Stub { name: "broken", works: false }))),
+        ("unconfigured", |_| Err(anyhow!("no API key"))),
+        ("first", |_| Ok(Box::new(Stub { name: "first", works: true }))),
+        ("second", |_| Ok(Box::new(Stub { name: "second", works: true }))),
+    ];
+
+    fn generate(backends: &[&str]) -> (Result<GeneratedImage>, Vec<&'static str>) {
+        TRIED.with_borrow_mut(Vec::clear);
+        let config = GeneratorConfig { backends: backends.iter().map(|name| name.to_string()).collect(), ..Default::default() };
+        let result = generate_with(STUBS, &GenerationRequest::default(), &config);
+        (result, TRIED.take())
+    }
+
+    #[test]
+    fn stops_at_the_first_backend_that_works() {
+        let (image, tried) = generate(&["second", "first"]);
+        let image = image.unwrap();
+        assert_eq!(image.bytes, b"second");
+        assert_eq!(image.metadata.backend, "second");
+        assert_eq!(tried, ["second"]);
+    }
+
+    #[test]
+    fn moves_on_after_an_error() {
+        let (image, tried) = generate(&["broken", "unconfigured", "nonexistent", "first", "second"]);
+        assert_eq!(image.unwrap().metadata.backend, "first");
+        assert_eq!(tried, ["broken", "first"]);
+    }
+
+    #[test]
+    fn reports_an_unknown_backend() {
+        let (image, tried) = generate(&["nonexistent"]);
+        assert_eq!(image.err().unwrap().to_string(), "nonexistent: unknown backend");
+        assert!(tried.is_empty());
+    }
+
+    #[test]
+    fn reports_every_error_when_all_backends_fail() {
+        let (image, tried) = generate(&["broken", "unconfigured", "nonexistent"]);
+        let error = image.err().unwrap();
+        assert_eq!(error.to_string(), "every backend failed");
+        assert_eq!(
+            error.root_cause().to_string(),
+            "broken: broken is down; unconfigured: no API key; nonexistent: unknown backend",
+        );
+        assert_eq!(tried, ["broken"]);
+    }
+
+    #[test]
+    fn needs_a_backend() {
+        let (image, _) = generate(&[]);
+        assert_eq!(image.err().unwrap().to_string(), "no generator backend is configured");
+    }
+
+    #[test]
+    fn knows_the_built_in_backends() {
+        assert_eq!(backend_names(), ["gemini", "local"]);
+    }
+}
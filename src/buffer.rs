@@ -0,0 +1,119 @@
+use std::{ffi::c_void, num::NonZeroUsize, os::fd::AsFd, ptr::NonNull};
+
+use nix::{sys::{memfd::{MemFdCreateFlag, memfd_create}, mman::{MapFlags, ProtFlags, mmap, munmap}}, unistd::ftruncate};
+use wayland_client::{QueueHandle, protocol::{wl_buffer, wl_shm}};
+
+use crate::{error::PaberError, state::AppState};
+
+/// One buffer on screen, one the compositor may still be reading and one to draw into
+const MAX_BUFFERS: usize = 3;
+
+/// A wl_buffer backed by its own memfd, mapped for as long as it lives.
+struct ShmBuffer {
+    buffer: wl_buffer::WlBuffer,
+    ptr: NonNull<c_void>,
+    len: usize,
+    /// Attached and not released by the compositor yet
+    busy: bool,
+}
+
+impl ShmBuffer {
+    fn new(shm: &wl_shm::WlShm, qh: &QueueHandle<AppState>, width: u32, height: u32, format: wl_shm::Format) -> Result<Self, PaberError> {
+        let len = (width * height * 4) as usize;
+        let length = NonZeroUsize::new(len).ok_or(PaberError::EmptySize)?;
+
+        let fd = memfd_create(
+            c"rust-wallpaper",
+            MemFdCreateFlag::empty()
+        ).map_err(PaberError::Buffer)?;
+
+        ftruncate(&fd, len as i64).map_err(PaberError::Buffer)?;
+
+        let ptr = unsafe {
+            mmap(
+                None,
+                length,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                Some(&fd),
+                0
+            ).map_err(PaberError::Buffer)?
+        };
+        // mmap never returns null on success
+        let ptr = NonNull::new(ptr).ok_or(PaberError::Buffer(nix::Error::ENOMEM))?;
+
+        let pool = shm.create_pool(fd.as_fd(), len as i32, qh, ());
+        let buffer = pool.create_buffer(
+            0,
+            width as i32,
+            height as i32,
+            (width * 4) as i32,
+            format,
+            qh,
+            ()
+        );
+        // The buffer keeps the memory alive on the compositor side, the pool and fd aren't needed anymore
+        pool.destroy();
+
+        Ok(ShmBuffer { buffer, ptr, len, busy: false })
+    }
+
+    fn canvas(&mut self) -> &mut [u32] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr() as *mut u32, self.len / 4) }
+    }
+}
+
+impl Drop for ShmBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+        if let Err(e) = unsafe { munmap(self.ptr.as_ptr(), self.len) } {
+            eprintln!("Failed to unmap a buffer: {e}");
+        }
+    }
+}
+
+/// The buffers of one wallpaper, reused for every frame drawn at the same size and format.
+#[derive(Default)]
+pub struct BufferPool {
+    buffers: Vec<ShmBuffer>,
+    size: (u32, u32),
+    format: Option<wl_shm::Format>,
+}
+
+impl BufferPool {
+    /// Paints into a buffer the compositor isn't reading and marks it busy.
+    /// Returns `None` when every buffer is still in use.
+    pub fn paint(&mut self, shm: &wl_shm::WlShm, qh: &QueueHandle<AppState>, width: u32, height: u32, format: wl_shm::Format, paint: impl FnOnce(&mut [u32])) -> Result<Option<wl_buffer::WlBuffer>, PaberError> {
+        if self.size != (width, height) || self.format != Some(format) {
+            // Buffers of the old size or format are of no use anymore
+            self.buffers.clear();
+            self.size = (width, height);
+            self.format = Some(format);
+        }
+
+        let index = match self.buffers.iter().position(|b| !b.busy) {
+            Some(index) => index,
+            None if self.buffers.len() < MAX_BUFFERS => {
+                self.buffers.push(ShmBuffer::new(shm, qh, width, height, format)?);
+                self.buffers.len() - 1
+            }
+            None => return Ok(None),
+        };
+
+        let buffer = &mut self.buffers[index];
+        paint(buffer.canvas());
+        buffer.busy = true;
+        Ok(Some(buffer.buffer.clone()))
+    }
+
+    /// Marks `buffer` as free again. Returns false if it doesn't belong to this pool.
+    pub fn release(&mut self, buffer: &wl_buffer::WlBuffer) -> bool {
+        match self.buffers.iter_mut().find(|b| b.buffer == *buffer) {
+            Some(b) => {
+                b.busy = false;
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -1,17 +1,13 @@
-use std::{os::fd::AsFd, process};
-use std::num::NonZeroUsize;
+use std::{sync::Arc, thread, time::Instant};
 
-use image::imageops::FilterType;
-use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+use image::{Rgba, RgbaImage};
 use wayland_client::{EventQueue, QueueHandle};
-use std::ffi::CStr;
-use wayland_client::{Connection, protocol::wl_shm};
+use wayland_client::{Connection, protocol::{wl_compositor, wl_shm}};
 use wayland_protocols_wlr::layer_shell::v1::client::{zwlr_layer_shell_v1, zwlr_layer_surface_v1};
-use nix::{sys::{memfd::{MemFdCreateFlag, memfd_create}}, unistd::ftruncate}; 
 
-use crate::state::{AppState};
+use crate::{animation::{Decoded, Playback}, buffer::BufferPool, color::{Color, Fill}, error::PaberError, render::{ScaleMode, is_opaque, scale_image}, state::{AppState, SinglePixelBuffer, Wallpaper}, transition::{Transition, TransitionKind}};
 
-pub fn build_state(conn: &Connection, event_queue: &mut EventQueue<AppState>) -> AppState {
+pub fn build_state(conn: &Connection, event_queue: &mut EventQueue<AppState>) -> Result<AppState, PaberError> {
     let qh = event_queue.handle();
 
     let display = conn.display();
@@ -22,33 +18,51 @@ pub fn build_state(conn: &Connection, event_queue: &mut EventQueue<AppState>) ->
 
     let mut state = AppState::new();
 
-    event_queue.roundtrip(&mut state).unwrap();
-    state
+    event_queue.roundtrip(&mut state)?;
+    // A second roundtrip collects the name and description of the bound outputs
+    event_queue.roundtrip(&mut state)?;
+    Ok(state)
 }
 
-pub fn build_surface(state: &mut AppState, qh: &QueueHandle<AppState>) {
+pub fn build_surface(state: &mut AppState, qh: &QueueHandle<AppState>) -> Result<(), PaberError> {
+    if state.compositor.is_none() {
+        return Err(PaberError::MissingGlobal("wl_compositor"));
+    }
+    if state.shm.is_none() {
+        return Err(PaberError::MissingGlobal("wl_shm"));
+    }
     if state.layer_shell.is_none() {
-        eprintln!("Error: This compositor does not support 'wlr_layer_shell_v1'.");
         eprintln!("Are you running Hyprland, Sway, or another wlroots-based compositor?");
         eprintln!("(GNOME and KDE often do not support this protocol).");
-        process::exit(1);
+        return Err(PaberError::MissingGlobal("zwlr_layer_shell_v1"));
     }
 
     println!("Success! Environment supports wallpapers. Continuing...");
 
-    let compositor = state.compositor.as_ref().expect("Compositor not found");
-    let layer_shell = state.layer_shell.as_ref().expect("Layer Shell not found");
+    create_missing_surfaces(state, qh);
+    Ok(())
+}
+
+/// Creates a layer surface for every output that has none yet, e.g. one that was just plugged in.
+pub fn create_missing_surfaces(state: &mut AppState, qh: &QueueHandle<AppState>) {
+    // Both were checked by `build_surface`
+    let (Some(compositor), Some(layer_shell)) = (&state.compositor, &state.layer_shell) else {
+        return;
+    };
 
-    let outputs = state.outputs.clone(); // Avoid borrowing issues while mutating state
+    let outputs: Vec<_> = state.outputs.iter()
+        .filter(|o| o.done && !state.wallpapers.iter().any(|w| w.output == o.wl_output))
+        .map(|o| (o.wl_output.clone(), o.scale))
+        .collect(); // Avoid borrowing issues while mutating state
 
-    for output in outputs {
+    for (output, scale) in outputs {
         let surface = compositor.create_surface(qh, ());
 
         let layer_surface = layer_shell.get_layer_surface(&surface,
             Some(&output), 
             zwlr_layer_shell_v1::Layer::Background,
             "paber".to_string(),
-            &qh,
+            qh,
             ());
 
         // Anchor to all 4 edges
@@ -62,134 +76,354 @@ pub fn build_surface(state: &mut AppState, qh: &QueueHandle<AppState>) {
         // don't rearrange other windows
         layer_surface.set_exclusive_zone(-1);
 
-        surface.commit();
-        state.wallpapers.push(crate::state::Wallpaper {
-            surface,
-            layer_surface,
-            width: 0, // Will be updated by configure event
-            height: 0,
-            configured: false,
-        });
-    }
+        let mut wallpaper = Wallpaper::new(output, surface, layer_surface, scale);
 
+        // The viewport scales single-pixel buffers, and with fractional scaling
+        // the buffer can be rendered at the exact scale
+        if let Some(viewporter) = &state.viewporter {
+            wallpaper.viewport = Some(viewporter.get_viewport(&wallpaper.surface, qh, ()));
+            if let Some(manager) = &state.fractional_scale_manager {
+                wallpaper.fractional = Some(manager.get_fractional_scale(&wallpaper.surface, qh, ()));
+            }
+        }
+
+        wallpaper.surface.commit();
+        state.wallpapers.push(wallpaper);
+    }
 }
 
-pub fn draw_plain(state: &AppState, qh: &QueueHandle<AppState>, wp_index: usize) {
+pub fn draw_plain(state: &mut AppState, qh: &QueueHandle<AppState>, fill: &Fill, wp_index: usize) {
     let wallpaper = &state.wallpapers[wp_index];
-    let shm = state.shm.as_ref().unwrap();
-    let size = (wallpaper.width * wallpaper.height * 4) as usize;
-
-    let length = NonZeroUsize::new(size).expect("Window size cannot be zero!");
-
-    let fd = memfd_create(
-        CStr::from_bytes_with_nul(b"rust-wallpaper\0").unwrap(), 
-        MemFdCreateFlag::empty()
-    ).unwrap();
-
-    ftruncate(&fd, size as i64).unwrap();
-
-    let ptr = unsafe { 
-        mmap(
-            None, 
-            length, 
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, 
-            MapFlags::MAP_SHARED, 
-            Some(&fd), 
-            0
-        ).unwrap() 
-    };
-
-    let canvas = unsafe {
-        std::slice::from_raw_parts_mut(ptr as *mut u32, (wallpaper.width * wallpaper.height) as usize)
-    };
+    if wallpaper.width == 0 || wallpaper.height == 0 {
+        state.wallpapers[wp_index].report(&PaberError::EmptySize);
+        return;
+    }
+    if let Fill::Solid(color) = *fill {
+        // A transition from what is on screen needs full size frames
+        let unchanged = wallpaper.solid == Some(color) && wallpaper.transition.is_none();
+        let on_screen = wallpaper.shown.is_some() || wallpaper.solid.is_some() || wallpaper.animation.is_some() || wallpaper.transition.is_some();
+        if (state.transition.kind == TransitionKind::None || unchanged || !on_screen) && commit_solid(state, qh, wp_index, color) {
+            return;
+        }
+    }
 
-    for pixel in canvas.iter_mut() {
-        *pixel = 0xFFFFFFFF;
+    let (width, height) = state.wallpapers[wp_index].buffer_size();
+    present(state, qh, Arc::new(fill.render(width, height)), wp_index);
+    if let Fill::Solid(color) = *fill {
+        // Switches to a single-pixel buffer once the transition is over
+        state.wallpapers[wp_index].solid = Some(color);
     }
+}
 
-    let pool = shm.create_pool(fd.as_fd(), size as i32, &qh, ());
+/// Shows `color` with a 1x1 buffer the viewport scales to the output, which
+/// saves allocating a buffer of the full size. Returns false when the compositor
+/// lacks wp_single_pixel_buffer_manager_v1 or wp_viewporter.
+fn commit_solid(state: &mut AppState, qh: &QueueHandle<AppState>, wp_index: usize, color: Color) -> bool {
+    let wallpaper = &mut state.wallpapers[wp_index];
+    let (Some(compositor), Some(manager), Some(_)) = (&state.compositor, &state.single_pixel_buffer_manager, &wallpaper.viewport) else {
+        return false;
+    };
 
-    let buffer = pool.create_buffer(
-        0, 
-        wallpaper.width as i32, 
-        wallpaper.height as i32, 
-        (wallpaper.width * 4) as i32, 
-        wl_shm::Format::Argb8888, 
-        &qh, 
-        ()
-    );
+    // Premultiplied channels, scaled from 0..=255 to the full u32 range
+    let channel = |c: u8| (c as u32 * color.a as u32 + 127) / 255 * 0x0101_0101;
+    let buffer = manager.create_u32_rgba_buffer(channel(color.r), channel(color.g), channel(color.b), color.a as u32 * 0x0101_0101, qh, SinglePixelBuffer);
 
+    set_opaque_region(compositor, qh, wallpaper, color.a == 255);
+    wallpaper.set_buffer_scale();
     wallpaper.surface.attach(Some(&buffer), 0, 0);
-
     wallpaper.surface.damage(0, 0, wallpaper.width as i32, wallpaper.height as i32);
-
     wallpaper.surface.commit();
+
+    // Nothing else is drawn until the source changes, the full size buffers can go
+    wallpaper.buffers = BufferPool::default();
+    wallpaper.shown = None;
+    wallpaper.transition = None;
+    wallpaper.animation = None;
+    wallpaper.stalled = false;
+    wallpaper.solid = Some(color);
+    wallpaper.error = None;
+    true
 }
 
-pub fn set_img(state: &AppState, qh: &QueueHandle<AppState>, image_path: &str, wp_index: usize) {
-    let wallpaper = &state.wallpapers[wp_index];
-    let shm = state.shm.as_ref().unwrap();
-    let size = (wallpaper.width * wallpaper.height * 4) as usize;
-
-    let length = NonZeroUsize::new(size).expect("Window size cannot be zero!");
-
-    let fd = memfd_create(
-        CStr::from_bytes_with_nul(b"rust-wallpaper\0").unwrap(), 
-        MemFdCreateFlag::empty()
-    ).unwrap();
-
-    ftruncate(&fd, size as i64).unwrap();
-
-    let ptr = unsafe { 
-        mmap(
-            None, 
-            length, 
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, 
-            MapFlags::MAP_SHARED, 
-            Some(&fd), 
-            0
-        ).unwrap() 
-    };
+/// Shows images on several wallpapers, grouping the wallpapers that show the same file.
+/// Wallpapers whose image can't be shown keep the previous one, and are returned with the reason.
+pub fn set_imgs(state: &mut AppState, qh: &QueueHandle<AppState>, images: Vec<(usize, String)>) -> Vec<(usize, PaberError)> {
+    let mut failed = Vec::new();
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for (index, path) in images {
+        match groups.iter_mut().find(|(p, _)| *p == path) {
+            Some((_, indexes)) => indexes.push(index),
+            None => groups.push((path, vec![index])),
+        }
+    }
+    for (path, indexes) in groups {
+        set_img(state, qh, &path, &indexes, &mut failed);
+    }
+    failed
+}
+
+/// Shows one image on the given wallpapers. It is decoded once and scaled once per
+/// distinct buffer size, mode and background, with the scaling done in parallel.
+fn set_img(state: &mut AppState, qh: &QueueHandle<AppState>, image_path: &str, wp_indexes: &[usize], failed: &mut Vec<(usize, PaberError)>) {
+    let mut sized = Vec::new();
+    for &index in wp_indexes {
+        let (width, height) = state.wallpapers[index].buffer_size();
+        if width == 0 || height == 0 {
+            failed.push((index, PaberError::EmptySize));
+            continue;
+        }
+        sized.push(index);
+    }
+    if sized.is_empty() {
+        return;
+    }
 
-    let canvas = unsafe {
-        std::slice::from_raw_parts_mut(ptr as *mut u32, (wallpaper.width * wallpaper.height) as usize)
+    // Whether the file is animated is decided once for all the wallpapers showing it
+    let (animation, decoded_still) = match state.animations.open(image_path) {
+        Ok(Decoded::Animated(animation)) => (Some(animation), None),
+        Ok(Decoded::Still(image)) => (None, image),
+        Err(e) => {
+            eprintln!("Failed to decode the animation, showing it as a still image: {e:#}");
+            (None, None)
+        }
     };
+    let mut still = Vec::new();
+    for index in sized {
+        let Some(animation) = &animation else {
+            still.push(index);
+            continue;
+        };
+        let wallpaper = &state.wallpapers[index];
+        let (width, height) = wallpaper.buffer_size();
+        match state.animations.scaled(image_path, animation, width, height, wallpaper.scale_mode, &wallpaper.background) {
+            Ok(scaled) => {
+                let playback = Playback::new(scaled, state.animation);
+                present(state, qh, Arc::new(playback.current().clone()), index);
+                state.wallpapers[index].animation = Some(playback);
+            }
+            Err(e) => {
+                eprintln!("Failed to scale the animation, showing it as a still image: {e:#}");
+                still.push(index);
+            }
+        }
+    }
+    if still.is_empty() {
+        return;
+    }
 
     println!("Loading image...");
-    let img = image::open(image_path).expect("Failed to open image file");
-    let resized_img = img.resize_exact(wallpaper.width, wallpaper.height, FilterType::Triangle);
-
-    let rgba_buffer = resized_img.to_rgba8();
+    // A missing or corrupt file leaves the previous wallpaper on screen
+    let img = match decoded_still.map_or_else(|| image::open(image_path), Ok) {
+        Ok(img) => img,
+        Err(e) => {
+            failed.extend(still.into_iter().map(|index| (index, PaberError::Image { path: image_path.into(), message: e.to_string() })));
+            return;
+        }
+    };
 
-    for (i, pixel) in rgba_buffer.pixels().enumerate() {
-        let [r, g, b, a] = pixel.0;
-        
-        // Pack the 4 bytes into one u32
-        // We shift bits to place them in A-R-G-B order for the u32 integer.
-        // When written to memory, Little Endian flips them to B-G-R-A.
-        canvas[i] = ((a as u32) << 24) | 
-                    ((r as u32) << 16) | 
-                    ((g as u32) << 8)  | 
-                    (b as u32);
+    // Outputs with the same buffer size and settings share the scaled pixels
+    let mut targets: Vec<(ScaledKey, Vec<usize>)> = Vec::new();
+    for index in still {
+        let wallpaper = &state.wallpapers[index];
+        let (width, height) = wallpaper.buffer_size();
+        let key = (width, height, wallpaper.scale_mode, wallpaper.background.clone());
+        match targets.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, indexes)) => indexes.push(index),
+            None => targets.push((key, vec![index])),
+        }
     }
 
+    // `None` where the scaling thread panicked
+    let scaled: Vec<Option<Arc<RgbaImage>>> = thread::scope(|scope| {
+        let handles: Vec<_> = targets.iter()
+            .map(|((width, height, mode, background), _)| {
+                let img = &img;
+                scope.spawn(move || Arc::new(scale_image(img, *width, *height, *mode, background)))
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().ok()).collect()
+    });
+
+    for (image, (_, indexes)) in scaled.into_iter().zip(targets) {
+        for index in indexes {
+            match &image {
+                Some(image) => present(state, qh, image.clone(), index),
+                None => failed.push((index, PaberError::Scaling(image_path.into()))),
+            }
+        }
+    }
     println!("Image drawn to buffer.");
+}
 
-    let pool = shm.create_pool(fd.as_fd(), size as i32, &qh, ());
+/// Buffer width and height, scaling mode and background
+type ScaledKey = (u32, u32, ScaleMode, Fill);
+
+/// Shows `image`, which has the wallpaper's buffer size, starting a transition
+/// from the previous image when one is configured.
+fn present(state: &mut AppState, qh: &QueueHandle<AppState>, image: Arc<RgbaImage>, wp_index: usize) {
+    let settings = state.transition;
+    let wallpaper = &mut state.wallpapers[wp_index];
+    wallpaper.error = None;
+    // A transition that is still running is cut short and continues from its target
+    let previous = wallpaper.transition.take().map(|t| t.to)
+        .or_else(|| wallpaper.animation.take().map(|a| Arc::new(a.current().clone())))
+        .or_else(|| wallpaper.shown.take());
+    // A colour shown with a single-pixel buffer is only rendered for the transition
+    let previous = match wallpaper.solid.take() {
+        Some(color) if previous.is_none() && settings.kind != TransitionKind::None => {
+            let (width, height) = image.dimensions();
+            Some(Arc::new(Fill::Solid(color).render(width, height)))
+        }
+        _ => previous,
+    };
+
+    match previous {
+        Some(from) if settings.kind != TransitionKind::None && from.dimensions() == image.dimensions() => {
+            wallpaper.transition = Some(Transition::new(from, image, settings));
+            draw_transition_frame(state, qh, wp_index);
+        }
+        _ => {
+            commit_frame(state, qh, wp_index, false, is_opaque(&image), |canvas| write_image(&image, canvas));
+            state.wallpapers[wp_index].shown = Some(image);
+        }
+    }
+}
+
+/// Handles the frame callback of a wallpaper, continuing its transition or animation.
+pub fn frame_done(state: &mut AppState, qh: &QueueHandle<AppState>, wp_index: usize) {
+    let wallpaper = &mut state.wallpapers[wp_index];
+    if wallpaper.transition.is_some() {
+        draw_transition_frame(state, qh, wp_index);
+        return;
+    }
+    if let Some(playback) = &mut wallpaper.animation {
+        playback.waiting = false;
+        if playback.is_due(Instant::now()) {
+            draw_animation_frame(state, qh, wp_index);
+        }
+    }
+}
+
+/// Shows the next frame of the wallpaper's animation, once the previous
+/// one was presented and its delay has passed.
+pub fn draw_animation_frame(state: &mut AppState, qh: &QueueHandle<AppState>, wp_index: usize) {
+    let wallpaper = &mut state.wallpapers[wp_index];
+    if wallpaper.transition.is_some() {
+        return;
+    }
+    let Some(mut playback) = wallpaper.animation.take() else {
+        return;
+    };
+    if playback.current().dimensions() != wallpaper.buffer_size() {
+        // The output changed size, the redraw that follows scales the frames again
+        return;
+    }
+    if playback.advance(Instant::now()) {
+        let opaque = playback.animation.opaque;
+        playback.waiting = commit_frame(state, qh, wp_index, true, opaque, |canvas| write_image(playback.current(), canvas));
+    }
+    state.wallpapers[wp_index].animation = Some(playback);
+}
+
+/// Draws the next frame of a running transition. Called again from the frame
+/// callback until the transition is over.
+fn draw_transition_frame(state: &mut AppState, qh: &QueueHandle<AppState>, wp_index: usize) {
+    let Some(transition) = state.wallpapers[wp_index].transition.take() else {
+        return;
+    };
+    let t = transition.progress(Instant::now());
+    if t >= 1.0 {
+        if let Some(color) = state.wallpapers[wp_index].solid
+            && commit_solid(state, qh, wp_index, color)
+        {
+            return;
+        }
+        commit_frame(state, qh, wp_index, false, is_opaque(&transition.to), |canvas| write_image(&transition.to, canvas));
+        state.wallpapers[wp_index].shown = Some(transition.to);
+        return;
+    }
+    if transition.to.dimensions() != state.wallpapers[wp_index].buffer_size() {
+        // The output changed size, the redraw that follows shows the new wallpaper
+        return;
+    }
+    commit_frame(state, qh, wp_index, true, transition.opaque, |canvas| transition.paint(t, canvas));
+    state.wallpapers[wp_index].transition = Some(transition);
+}
 
-    let buffer = pool.create_buffer(
-        0, 
-        wallpaper.width as i32, 
-        wallpaper.height as i32, 
-        (wallpaper.width * 4) as i32, 
-        wl_shm::Format::Argb8888, 
-        &qh, 
-        ()
-    );
+/// Attaches a buffer filled by `paint` and commits it. With `frame` the
+/// compositor is asked to tell when it is a good time to draw the next one.
+/// An `opaque` frame is sent without alpha and marked opaque, so the compositor
+/// doesn't blend it with what is behind.
+///
+/// Returns false when every buffer of the wallpaper is still in use. The wallpaper
+/// is then drawn again by `buffer_released`.
+fn commit_frame(state: &mut AppState, qh: &QueueHandle<AppState>, wp_index: usize, frame: bool, opaque: bool, paint: impl FnOnce(&mut [u32])) -> bool {
+    let wallpaper = &mut state.wallpapers[wp_index];
+    // Both were checked by `build_surface`
+    let (Some(shm), Some(compositor)) = (&state.shm, &state.compositor) else {
+        return false;
+    };
+    let (width, height) = wallpaper.buffer_size();
+    let format = if opaque { wl_shm::Format::Xrgb8888 } else { wl_shm::Format::Argb8888 };
+
+    let buffer = match wallpaper.buffers.paint(shm, qh, width, height, format, paint) {
+        Ok(Some(buffer)) => buffer,
+        Ok(None) => {
+            wallpaper.stalled = true;
+            return false;
+        }
+        Err(e) => {
+            wallpaper.report(&e);
+            return false;
+        }
+    };
 
+    set_opaque_region(compositor, qh, wallpaper, opaque);
+    wallpaper.set_buffer_scale();
     wallpaper.surface.attach(Some(&buffer), 0, 0);
 
     wallpaper.surface.damage(0, 0, wallpaper.width as i32, wallpaper.height as i32);
 
+    if frame {
+        wallpaper.surface.frame(qh, wallpaper.surface.clone());
+    }
     wallpaper.surface.commit();
+    true
+}
+
+/// Marks the whole surface opaque, or nothing when the next buffer has transparency.
+fn set_opaque_region(compositor: &wl_compositor::WlCompositor, qh: &QueueHandle<AppState>, wallpaper: &Wallpaper, opaque: bool) {
+    if opaque {
+        let region = compositor.create_region(qh, ());
+        region.add(0, 0, wallpaper.width as i32, wallpaper.height as i32);
+        wallpaper.surface.set_opaque_region(Some(&region));
+        region.destroy();
+    } else {
+        wallpaper.surface.set_opaque_region(None);
+    }
+}
+
+/// Draws what couldn't be drawn while all buffers of a wallpaper were busy.
+pub fn buffer_released(state: &mut AppState, qh: &QueueHandle<AppState>, wp_index: usize) {
+    let wallpaper = &mut state.wallpapers[wp_index];
+    if !wallpaper.stalled {
+        return;
+    }
+    wallpaper.stalled = false;
+    if wallpaper.transition.is_some() {
+        draw_transition_frame(state, qh, wp_index);
+    } else if wallpaper.animation.is_some() {
+        // The next frame is drawn when it is due
+    } else if let Some(image) = wallpaper.shown.take() {
+        if image.dimensions() == wallpaper.buffer_size() {
+            commit_frame(state, qh, wp_index, false, is_opaque(&image), |canvas| write_image(&image, canvas));
+        }
+        state.wallpapers[wp_index].shown = Some(image);
+    }
+}
+
+fn write_image(image: &RgbaImage, canvas: &mut [u32]) {
+    for (pixel, &Rgba([r, g, b, a])) in canvas.iter_mut().zip(image.pixels()) {
+        *pixel = Color { r, g, b, a }.to_argb8888();
+    }
 }
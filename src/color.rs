@@ -0,0 +1,615 @@
+use std::{f32::consts::PI, fmt, str::FromStr};
+
+use image::{Rgba, RgbaImage};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b, a: 255 }
+    }
+
+    /// Parses a CSS colour: hex (#rgb, #rgba, #rrggbb, #rrggbbaa), rgb()/rgba(),
+    /// hsl()/hsla() or a named colour.
+    pub fn parse(input: &str) -> Result<Color, String> {
+        let s = input.trim().to_ascii_lowercase();
+
+        if let Some(hex) = s.strip_prefix('#') {
+            return parse_hex(hex).ok_or_else(|| format!("invalid hex colour '{input}'"));
+        }
+        if let Some(args) = function_args(&s, &["rgb", "rgba"]) {
+            return parse_rgb(args).ok_or_else(|| format!("invalid rgb() colour '{input}'"));
+        }
+        if let Some(args) = function_args(&s, &["hsl", "hsla"]) {
+            return parse_hsl(args).ok_or_else(|| format!("invalid hsl() colour '{input}'"));
+        }
+        named(&s).ok_or_else(|| format!("unknown colour '{input}'"))
+    }
+
+    /// Packs the colour into a u32 in A-R-G-B order (B-G-R-A in little endian memory).
+    /// Wayland expects premultiplied alpha, so the colour channels are scaled by it.
+    pub fn to_argb8888(self) -> u32 {
+        let premultiply = |c: u8| (c as u32 * self.a as u32 + 127) / 255;
+        ((self.a as u32) << 24) | (premultiply(self.r) << 16) | (premultiply(self.g) << 8) | premultiply(self.b)
+    }
+
+    fn lerp(self, other: Color, t: f32) -> Color {
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        Color {
+            r: mix(self.r, other.r),
+            g: mix(self.g, other.g),
+            b: mix(self.b, other.b),
+            a: mix(self.a, other.a),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorStop {
+    pub color: Color,
+    /// Position along the gradient line, 0.0 to 1.0
+    pub position: f32,
+}
+
+/// Something that can paint a whole surface: a solid colour or a gradient.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fill {
+    Solid(Color),
+    /// CSS angle: 0 points up, 90 points right
+    Linear { angle: f32, stops: Vec<ColorStop> },
+    /// Center is relative to the surface (0.0 to 1.0)
+    Radial { circle: bool, center: (f32, f32), stops: Vec<ColorStop> },
+    Conic { from: f32, center: (f32, f32), stops: Vec<ColorStop> },
+}
+
+impl Fill {
+    /// Parses a colour or one of `linear-gradient(...)`, `radial-gradient(...)`
+    /// and `conic-gradient(...)` using CSS syntax.
+    pub fn parse(input: &str) -> Result<Fill, String> {
+        let s = input.trim().to_ascii_lowercase();
+
+        if let Some(args) = function_args(&s, &["linear-gradient"]) {
+            let mut parts = split_top_level(args);
+            let mut angle = 180.0;
+            if let Some(first) = parts.first()
+                && let Some(a) = parse_direction(first)
+            {
+                angle = a;
+                parts.remove(0);
+            }
+            return Ok(Fill::Linear { angle, stops: parse_stops(&parts)? });
+        }
+
+        if let Some(args) = function_args(&s, &["radial-gradient"]) {
+            let mut parts = split_top_level(args);
+            let mut circle = false;
+            let mut center = (0.5, 0.5);
+            if let Some(first) = parts.first()
+                && !is_stop(first)
+            {
+                let (shape, at) = first.split_once("at ").unwrap_or((first, ""));
+                match shape.trim() {
+                    "circle" => circle = true,
+                    "ellipse" | "" => {}
+                    other => return Err(format!("unknown radial shape '{other}'")),
+                }
+                if !at.trim().is_empty() {
+                    center = parse_position(at)?;
+                }
+                parts.remove(0);
+            }
+            return Ok(Fill::Radial { circle, center, stops: parse_stops(&parts)? });
+        }
+
+        if let Some(args) = function_args(&s, &["conic-gradient"]) {
+            let mut parts = split_top_level(args);
+            let mut from = 0.0;
+            let mut center = (0.5, 0.5);
+            if let Some(first) = parts.first() {
+                let first = first.trim();
+                if first.starts_with("from") || first.starts_with("at") {
+                    let (from_part, at_part) = first.split_once("at ").unwrap_or((first, ""));
+                    if let Some(angle) = from_part.trim().strip_prefix("from") {
+                        from = parse_angle(angle.trim()).ok_or_else(|| format!("invalid angle '{}'", angle.trim()))?;
+                    }
+                    if !at_part.trim().is_empty() {
+                        center = parse_position(at_part)?;
+                    }
+                    parts.remove(0);
+                }
+            }
+            return Ok(Fill::Conic { from, center, stops: parse_stops(&parts)? });
+        }
+
+        Color::parse(&s).map(Fill::Solid)
+    }
+
+    pub fn color_at(&self, x: u32, y: u32, width: u32, height: u32) -> Color {
+        let (w, h) = (width as f32, height as f32);
+        // Sample at the pixel center
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+
+        match self {
+            Fill::Solid(color) => *color,
+            Fill::Linear { angle, stops } => {
+                let rad = angle.to_radians();
+                let (dx, dy) = (rad.sin(), -rad.cos());
+                let length = (w * dx).abs() + (h * dy).abs();
+                let t = ((px - w / 2.0) * dx + (py - h / 2.0) * dy) / length + 0.5;
+                sample(stops, t)
+            }
+            Fill::Radial { circle, center, stops } => {
+                let (cx, cy) = (center.0 * w, center.1 * h);
+                let far_x = cx.max(w - cx);
+                let far_y = cy.max(h - cy);
+                let t = if *circle {
+                    let radius = (far_x * far_x + far_y * far_y).sqrt();
+                    ((px - cx).powi(2) + (py - cy).powi(2)).sqrt() / radius
+                } else {
+                    let (rx, ry) = (far_x * 2f32.sqrt(), far_y * 2f32.sqrt());
+                    (((px - cx) / rx).powi(2) + ((py - cy) / ry).powi(2)).sqrt()
+                };
+                sample(stops, t)
+            }
+            Fill::Conic { from, center, stops } => {
+                let (cx, cy) = (center.0 * w, center.1 * h);
+                // Clockwise from the top, like CSS
+                let theta = (px - cx).atan2(cy - py) - from.to_radians();
+                sample(stops, (theta / (2.0 * PI)).rem_euclid(1.0))
+            }
+        }
+    }
+
+    /// Renders the fill into a new RGBA image of the given size.
+    pub fn render(&self, width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            let c = self.color_at(x, y, width, height);
+            Rgba([c.r, c.g, c.b, c.a])
+        })
+    }
+}
+
+impl FromStr for Fill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Fill::parse(s)
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        if self.a != 255 {
+            write!(f, "{:02x}", self.a)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the fill back in the CSS syntax `Fill::parse` accepts.
+impl fmt::Display for Fill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (stops, prefix) = match self {
+            Fill::Solid(color) => return write!(f, "{color}"),
+            Fill::Linear { angle, stops } => (stops, format!("linear-gradient({angle}deg")),
+            Fill::Radial { circle, center, stops } => {
+                let shape = if *circle { "circle" } else { "ellipse" };
+                (stops, format!("radial-gradient({shape} at {}% {}%", center.0 * 100.0, center.1 * 100.0))
+            }
+            Fill::Conic { from, center, stops } => {
+                (stops, format!("conic-gradient(from {from}deg at {}% {}%", center.0 * 100.0, center.1 * 100.0))
+            }
+        };
+        write!(f, "{prefix}")?;
+        for stop in stops {
+            write!(f, ", {} {}%", stop.color, stop.position * 100.0)?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn sample(stops: &[ColorStop], t: f32) -> Color {
+    let first = &stops[0];
+    if t <= first.position {
+        return first.color;
+    }
+    for pair in stops.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        if t <= b.position {
+            let span = b.position - a.position;
+            if span <= f32::EPSILON {
+                return b.color;
+            }
+            return a.color.lerp(b.color, (t - a.position) / span);
+        }
+    }
+    stops[stops.len() - 1].color
+}
+
+/// Returns the argument list if `s` is a call to one of `names`, e.g. `rgb(1, 2, 3)`.
+fn function_args<'a>(s: &'a str, names: &[&str]) -> Option<&'a str> {
+    let open = s.find('(')?;
+    let name = s[..open].trim();
+    if !names.contains(&name) {
+        return None;
+    }
+    s[open + 1..].trim_end().strip_suffix(')')
+}
+
+/// Splits on commas that are not nested inside parentheses.
+fn split_top_level(s: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in s.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() {
+        parts.push(current.trim().to_string());
+    }
+    parts
+}
+
+fn parse_stops(parts: &[String]) -> Result<Vec<ColorStop>, String> {
+    if parts.len() < 2 {
+        return Err("a gradient needs at least two colour stops".to_string());
+    }
+
+    let mut colors = Vec::new();
+    let mut positions: Vec<Option<f32>> = Vec::new();
+    for part in parts {
+        let (color, position) = split_stop(part)?;
+        colors.push(color);
+        positions.push(position);
+    }
+
+    // Resolve missing positions the way CSS does: the ends default to 0% and 100%,
+    // gaps in between are spread evenly and positions never go backwards.
+    let last = positions.len() - 1;
+    positions[0].get_or_insert(0.0);
+    positions[last].get_or_insert(1.0);
+    let mut i = 1;
+    while i < last {
+        if positions[i].is_none() {
+            let start = i - 1;
+            let end = (i..=last).find(|&j| positions[j].is_some()).unwrap();
+            let (from, to) = (positions[start].unwrap(), positions[end].unwrap());
+            for (j, position) in positions.iter_mut().enumerate().take(end).skip(i) {
+                *position = Some(from + (to - from) * (j - start) as f32 / (end - start) as f32);
+            }
+            i = end;
+        }
+        i += 1;
+    }
+
+    let mut max = f32::MIN;
+    Ok(colors
+        .into_iter()
+        .zip(positions)
+        .map(|(color, position)| {
+            max = max.max(position.unwrap());
+            ColorStop { color, position: max }
+        })
+        .collect())
+}
+
+fn split_stop(part: &str) -> Result<(Color, Option<f32>), String> {
+    // The position, if any, follows the last space outside of parentheses
+    if let Some(idx) = part.rfind(' ') {
+        let (color, position) = part.split_at(idx);
+        if (!color.contains('(') || color.contains(')'))
+            && let Some(pos) = parse_percentage(position.trim())
+        {
+            return Ok((Color::parse(color)?, Some(pos)));
+        }
+    }
+    Ok((Color::parse(part)?, None))
+}
+
+fn is_stop(part: &str) -> bool {
+    split_stop(part).is_ok()
+}
+
+pub fn parse_direction(s: &str) -> Option<f32> {
+    if let Some(side) = s.trim().strip_prefix("to ") {
+        let mut words: Vec<&str> = side.split_whitespace().collect();
+        words.sort();
+        return match words.as_slice() {
+            ["top"] => Some(0.0),
+            ["right", "top"] => Some(45.0),
+            ["right"] => Some(90.0),
+            ["bottom", "right"] => Some(135.0),
+            ["bottom"] => Some(180.0),
+            ["bottom", "left"] => Some(225.0),
+            ["left"] => Some(270.0),
+            ["left", "top"] => Some(315.0),
+            _ => None,
+        };
+    }
+    parse_angle(s)
+}
+
+/// Parses an angle in degrees (`deg`), turns (`turn`), radians (`rad`) or gradians (`grad`).
+fn parse_angle(s: &str) -> Option<f32> {
+    let s = s.trim();
+    if let Some(v) = s.strip_suffix("deg") {
+        return v.trim().parse().ok();
+    }
+    if let Some(v) = s.strip_suffix("grad") {
+        return v.trim().parse::<f32>().ok().map(|g| g * 0.9);
+    }
+    if let Some(v) = s.strip_suffix("rad") {
+        return v.trim().parse::<f32>().ok().map(|r| r.to_degrees());
+    }
+    if let Some(v) = s.strip_suffix("turn") {
+        return v.trim().parse::<f32>().ok().map(|t| t * 360.0);
+    }
+    None
+}
+
+pub fn parse_position(s: &str) -> Result<(f32, f32), String> {
+    let mut words = s.split_whitespace().map(|w| match w {
+        "left" | "top" => Some(0.0),
+        "center" => Some(0.5),
+        "right" | "bottom" => Some(1.0),
+        other => parse_percentage(other),
+    });
+    let x = words.next().flatten();
+    let y = words.next().unwrap_or(Some(0.5));
+    match (x, y) {
+        (Some(x), Some(y)) => Ok((x, y)),
+        _ => Err(format!("invalid position '{}'", s.trim())),
+    }
+}
+
+fn parse_percentage(s: &str) -> Option<f32> {
+    s.strip_suffix('%')?.trim().parse::<f32>().ok().map(|p| p / 100.0)
+}
+
+fn parse_hex(hex: &str) -> Option<Color> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digit = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok().map(|v| v * 17);
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    match hex.len() {
+        3 => Some(Color::rgb(digit(0)?, digit(1)?, digit(2)?)),
+        4 => Some(Color { r: digit(0)?, g: digit(1)?, b: digit(2)?, a: digit(3)? }),
+        6 => Some(Color::rgb(byte(0)?, byte(2)?, byte(4)?)),
+        8 => Some(Color { r: byte(0)?, g: byte(2)?, b: byte(4)?, a: byte(6)? }),
+        _ => None,
+    }
+}
+
+/// Splits both the legacy `a, b, c, d` and the modern `a b c / d` argument forms.
+fn color_components(args: &str) -> Option<(Vec<&str>, Option<&str>)> {
+    if args.contains(',') {
+        let mut parts: Vec<&str> = args.split(',').map(str::trim).collect();
+        let alpha = if parts.len() == 4 { parts.pop() } else { None };
+        return (parts.len() == 3).then_some((parts, alpha));
+    }
+    let (channels, alpha) = match args.split_once('/') {
+        Some((c, a)) => (c, Some(a.trim())),
+        None => (args, None),
+    };
+    let parts: Vec<&str> = channels.split_whitespace().collect();
+    (parts.len() == 3).then_some((parts, alpha))
+}
+
+fn parse_alpha(s: Option<&str>) -> Option<u8> {
+    let Some(s) = s else {
+        return Some(255);
+    };
+    let value = parse_percentage(s).or_else(|| s.parse().ok())?;
+    Some((value.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+fn parse_rgb(args: &str) -> Option<Color> {
+    let (parts, alpha) = color_components(args)?;
+    let channel = |s: &str| {
+        let value = match parse_percentage(s) {
+            Some(p) => p * 255.0,
+            None => s.parse::<f32>().ok()?,
+        };
+        Some(value.clamp(0.0, 255.0).round() as u8)
+    };
+    Some(Color {
+        r: channel(parts[0])?,
+        g: channel(parts[1])?,
+        b: channel(parts[2])?,
+        a: parse_alpha(alpha)?,
+    })
+}
+
+fn parse_hsl(args: &str) -> Option<Color> {
+    let (parts, alpha) = color_components(args)?;
+    let hue = parse_angle(parts[0]).or_else(|| parts[0].parse().ok())?;
+    let saturation = parse_percentage(parts[1])?.clamp(0.0, 1.0);
+    let lightness = parse_percentage(parts[2])?.clamp(0.0, 1.0);
+
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let to_byte = |v: f32| ((v + m) * 255.0).round() as u8;
+    Some(Color { r: to_byte(r), g: to_byte(g), b: to_byte(b), a: parse_alpha(alpha)? })
+}
+
+fn named(name: &str) -> Option<Color> {
+    if name == "transparent" {
+        return Some(Color { r: 0, g: 0, b: 0, a: 0 });
+    }
+    NAMED_COLORS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, hex)| Color::rgb((hex >> 16) as u8, (hex >> 8) as u8, *hex as u8))
+}
+
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff), ("antiquewhite", 0xfaebd7), ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4), ("azure", 0xf0ffff), ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4), ("black", 0x000000), ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff), ("blueviolet", 0x8a2be2), ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887), ("cadetblue", 0x5f9ea0), ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e), ("coral", 0xff7f50), ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc), ("crimson", 0xdc143c), ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b), ("darkcyan", 0x008b8b), ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9), ("darkgreen", 0x006400), ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b), ("darkmagenta", 0x8b008b), ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00), ("darkorchid", 0x9932cc), ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a), ("darkseagreen", 0x8fbc8f), ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f), ("darkslategrey", 0x2f4f4f), ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3), ("deeppink", 0xff1493), ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969), ("dimgrey", 0x696969), ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222), ("floralwhite", 0xfffaf0), ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff), ("gainsboro", 0xdcdcdc), ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700), ("goldenrod", 0xdaa520), ("gray", 0x808080),
+    ("green", 0x008000), ("greenyellow", 0xadff2f), ("grey", 0x808080),
+    ("honeydew", 0xf0fff0), ("hotpink", 0xff69b4), ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082), ("ivory", 0xfffff0), ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa), ("lavenderblush", 0xfff0f5), ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd), ("lightblue", 0xadd8e6), ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff), ("lightgoldenrodyellow", 0xfafad2), ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90), ("lightgrey", 0xd3d3d3), ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a), ("lightseagreen", 0x20b2aa), ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899), ("lightslategrey", 0x778899), ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0), ("lime", 0x00ff00), ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6), ("magenta", 0xff00ff), ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa), ("mediumblue", 0x0000cd), ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db), ("mediumseagreen", 0x3cb371), ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a), ("mediumturquoise", 0x48d1cc), ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970), ("mintcream", 0xf5fffa), ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5), ("navajowhite", 0xffdead), ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6), ("olive", 0x808000), ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500), ("orangered", 0xff4500), ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa), ("palegreen", 0x98fb98), ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093), ("papayawhip", 0xffefd5), ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f), ("pink", 0xffc0cb), ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6), ("purple", 0x800080), ("rebeccapurple", 0x663399),
+    ("red", 0xff0000), ("rosybrown", 0xbc8f8f), ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513), ("salmon", 0xfa8072), ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57), ("seashell", 0xfff5ee), ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0), ("skyblue", 0x87ceeb), ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090), ("slategrey", 0x708090), ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f), ("steelblue", 0x4682b4), ("tan", 0xd2b48c),
+    ("teal", 0x008080), ("thistle", 0xd8bfd8), ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0), ("violet", 0xee82ee), ("wheat", 0xf5deb3),
+    ("white", 0xffffff), ("whitesmoke", 0xf5f5f5), ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color { r, g, b, a }
+    }
+
+    #[test]
+    fn parses_hex_colours() {
+        assert_eq!(Color::parse("#f80"), Ok(Color::rgb(255, 136, 0)));
+        assert_eq!(Color::parse("#f808"), Ok(rgba(255, 136, 0, 136)));
+        assert_eq!(Color::parse("#1E90FF"), Ok(Color::rgb(30, 144, 255)));
+        assert_eq!(Color::parse("#1e90ff80"), Ok(rgba(30, 144, 255, 128)));
+        assert!(Color::parse("#12345").is_err());
+        assert!(Color::parse("#ggg").is_err());
+    }
+
+    #[test]
+    fn parses_rgb_and_hsl() {
+        assert_eq!(Color::parse("rgb(30, 144, 255)"), Ok(Color::rgb(30, 144, 255)));
+        assert_eq!(Color::parse("rgba(30, 144, 255, 0.5)"), Ok(rgba(30, 144, 255, 128)));
+        assert_eq!(Color::parse("rgb(100% 0% 50% / 25%)"), Ok(rgba(255, 0, 128, 64)));
+        assert_eq!(Color::parse("hsl(120, 100%, 50%)"), Ok(Color::rgb(0, 255, 0)));
+        assert_eq!(Color::parse("hsl(0.5turn 100% 50%)"), Ok(Color::rgb(0, 255, 255)));
+        assert!(Color::parse("rgb(1, 2)").is_err());
+        assert!(Color::parse("hsl(120, 100, 50)").is_err());
+    }
+
+    #[test]
+    fn parses_named_colours() {
+        assert_eq!(Color::parse("RebeccaPurple"), Ok(Color::rgb(102, 51, 153)));
+        assert_eq!(Color::parse("transparent"), Ok(rgba(0, 0, 0, 0)));
+        assert!(Color::parse("notacolour").is_err());
+    }
+
+    #[test]
+    fn packs_premultiplied_argb() {
+        assert_eq!(Color::rgb(30, 144, 255).to_argb8888(), 0xff1e90ff);
+        assert_eq!(Color::rgb(0, 0, 0).to_argb8888(), 0xff000000);
+        assert_eq!(rgba(255, 255, 255, 0).to_argb8888(), 0);
+        assert_eq!(rgba(30, 144, 255, 0).to_argb8888(), 0);
+        assert_eq!(rgba(255, 255, 255, 128).to_argb8888(), 0x80808080);
+        assert_eq!(rgba(255, 136, 0, 128).to_argb8888(), 0x80804400);
+        assert_eq!(rgba(1, 2, 3, 128).to_argb8888(), 0x80010102);
+    }
+
+    #[test]
+    fn parses_gradients() {
+        let black = Color::rgb(0, 0, 0);
+        let white = Color::rgb(255, 255, 255);
+        assert_eq!(Fill::parse("linear-gradient(to right, black, white)"), Ok(Fill::Linear {
+            angle: 90.0,
+            stops: vec![ColorStop { color: black, position: 0.0 }, ColorStop { color: white, position: 1.0 }],
+        }));
+        assert!(matches!(
+            Fill::parse("radial-gradient(circle at 25% 75%, red, blue)"),
+            Ok(Fill::Radial { circle: true, center: (0.25, 0.75), .. })
+        ));
+        assert!(matches!(Fill::parse("conic-gradient(from 90deg, red, blue)"), Ok(Fill::Conic { from: 90.0, .. })));
+        assert_eq!(Fill::parse("white"), Ok(Fill::Solid(white)));
+        assert!(Fill::parse("radial-gradient(square, red, blue)").is_err());
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for input in ["#1e90ff80", "linear-gradient(45deg, red, blue 40%, white)", "radial-gradient(circle at 25% 75%, red, blue)", "conic-gradient(from 90deg at 50% 50%, red, blue)"] {
+            let fill = Fill::parse(input).unwrap();
+            assert_eq!(Fill::parse(&fill.to_string()), Ok(fill), "{input}");
+        }
+    }
+
+    #[test]
+    fn renders_solid_fills() {
+        let image = Fill::Solid(rgba(1, 2, 3, 4)).render(3, 2);
+        assert_eq!(image.dimensions(), (3, 2));
+        assert!(image.pixels().all(|&pixel| pixel == Rgba([1, 2, 3, 4])));
+    }
+
+    #[test]
+    fn renders_gradients_along_their_direction() {
+        let image = Fill::parse("linear-gradient(to right, black, white)").unwrap().render(4, 1);
+        let reds: Vec<u8> = image.pixels().map(|pixel| pixel.0[0]).collect();
+        assert!(reds.windows(2).all(|pair| pair[0] < pair[1]), "{reds:?}");
+        assert!(reds[0] < 64 && reds[3] > 191, "{reds:?}");
+
+        let image = Fill::parse("linear-gradient(black, white)").unwrap().render(1, 4);
+        assert!(image.get_pixel(0, 0).0[0] < image.get_pixel(0, 3).0[0]);
+    }
+}
@@ -0,0 +1,536 @@
+use std::{collections::BTreeMap, env, fs, ops::Range, path::{Path, PathBuf}, time::Duration};
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{animation::AnimationSettings, backend::backend_names, color::Fill, playlist::{Order, ScanOptions, parse_glob}, render::ScaleMode, schedule::Schedule, source::Source, transition::{Easing, TransitionKind, TransitionSettings}};
+
+/// Settings from `config.toml`, validated and converted to the types paber uses.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Where generated images are stored, used when PABER_HOME is not set
+    pub home: Option<String>,
+    pub mode: Option<ScaleMode>,
+    pub background: Option<Fill>,
+    /// How often cycles switch images
+    pub interval: Option<Schedule>,
+    pub order: Option<Order>,
+    pub scan: ScanOptions,
+    pub generator: GeneratorConfig,
+    pub prompt: PromptConfig,
+    pub transition: TransitionSettings,
+    pub animation: AnimationSettings,
+    pub outputs: Vec<OutputConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    /// Names from `backend::BACKENDS`, tried in order until one generates an image
+    pub backends: Vec<String>,
+    /// Used when GEMINI_API_KEY is not set
+    pub api_key: Option<String>,
+    /// How often generated wallpapers are generated again, only once when unset
+    pub interval: Option<Schedule>,
+    /// Images generated ahead of the next switch of a scheduled generated wallpaper
+    pub prefetch: usize,
+    /// Makes generation repeatable where the backend supports it
+    pub seed: Option<u64>,
+    pub gemini: GeminiConfig,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            backends: vec!["gemini".to_string()],
+            api_key: None,
+            interval: None,
+            prefetch: 1,
+            seed: None,
+            gemini: GeminiConfig::default(),
+        }
+    }
+}
+
+/// How the gemini backend talks to the API.
+#[derive(Debug, Clone)]
+pub struct GeminiConfig {
+    pub model: String,
+    /// API root the model path is appended to, e.g. a gateway or a mock server
+    pub base_url: String,
+    pub timeout: Duration,
+    /// HTTP proxy for all requests, the environment's proxy settings apply when unset
+    pub proxy: Option<String>,
+    /// One of `ASPECT_RATIOS`
+    pub aspect_ratio: Option<String>,
+    /// Only some models support it, e.g. "2K"
+    pub image_size: Option<String>,
+    pub candidate_count: Option<u32>,
+    pub temperature: Option<f64>,
+    /// Threshold per harm category, e.g. HARM_CATEGORY_HARASSMENT = "BLOCK_NONE"
+    pub safety_settings: BTreeMap<String, String>,
+}
+
+impl Default for GeminiConfig {
+    fn default() -> Self {
+        GeminiConfig {
+            model: "gemini-2.5-flash-image".to_string(),
+            base_url: "https://generativelanguage.googleapis.com/v1beta".to_string(),
+            timeout: Duration::from_secs(30),
+            proxy: None,
+            aspect_ratio: None,
+            image_size: None,
+            candidate_count: None,
+            temperature: None,
+            safety_settings: BTreeMap::new(),
+        }
+    }
+}
+
+/// Aspect ratios Gemini image models can generate.
+pub const ASPECT_RATIOS: &[&str] = &["1:1", "2:3", "3:2", "3:4", "4:3", "4:5", "5:4", "9:16", "16:9", "21:9"];
+
+#[derive(Debug)]
+pub struct PromptConfig {
+    /// Prompt for generated sources that don't bring their own
+    pub text: Option<String>,
+    /// Adds the user name, time of day and date to the prompt
+    pub enrich: bool,
+    /// What generated images shouldn't show
+    pub negative: Option<String>,
+}
+
+impl Default for PromptConfig {
+    fn default() -> Self {
+        PromptConfig { text: None, enrich: true, negative: None }
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputConfig {
+    /// Monitor selector, same syntax as --monitors
+    pub monitor: String,
+    pub source: Option<Source>,
+    pub mode: Option<ScaleMode>,
+    pub background: Option<Fill>,
+    pub interval: Option<Schedule>,
+    pub order: Option<Order>,
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    /// Line and column, both starting at 1
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+/// Formats errors as `path:line:column: message`, the format editors understand.
+pub fn format_errors(path: &Path, errors: &[ConfigError]) -> Vec<String> {
+    errors.iter().map(|error| match error.location {
+        Some((line, column)) => format!("{}:{line}:{column}: {}", path.display(), error.message),
+        None => format!("{}: {}", path.display(), error.message),
+    }).collect()
+}
+
+pub fn report_errors(path: &Path, errors: &[ConfigError]) {
+    format_errors(path, errors).iter().for_each(|e| eprintln!("{e}"));
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    home: Option<String>,
+    #[serde(default)]
+    defaults: DefaultsFile,
+    #[serde(default)]
+    cycle: CycleFile,
+    #[serde(default)]
+    generator: GeneratorFile,
+    #[serde(default)]
+    prompt: PromptFile,
+    #[serde(default)]
+    transition: TransitionFile,
+    #[serde(default)]
+    animation: AnimationFile,
+    #[serde(default, rename = "output")]
+    outputs: Vec<OutputFile>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DefaultsFile {
+    mode: Option<Spanned<String>>,
+    background: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CycleFile {
+    interval: Option<Spanned<IntervalFile>>,
+    order: Option<Spanned<String>>,
+    recursive: Option<bool>,
+    max_depth: Option<usize>,
+    #[serde(default)]
+    include: Vec<Spanned<String>>,
+    #[serde(default)]
+    exclude: Vec<Spanned<String>>,
+    follow_symlinks: Option<bool>,
+    hidden: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct GeneratorFile {
+    backend: Option<Spanned<BackendFile>>,
+    api_key: Option<String>,
+    interval: Option<Spanned<IntervalFile>>,
+    prefetch: Option<usize>,
+    seed: Option<u64>,
+    #[serde(default)]
+    gemini: GeminiFile,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct GeminiFile {
+    model: Option<String>,
+    base_url: Option<Spanned<String>>,
+    /// Seconds
+    timeout: Option<Spanned<f64>>,
+    proxy: Option<Spanned<String>>,
+    aspect_ratio: Option<Spanned<String>>,
+    image_size: Option<String>,
+    candidate_count: Option<Spanned<u32>>,
+    temperature: Option<Spanned<f64>>,
+    #[serde(default)]
+    safety_settings: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct PromptFile {
+    text: Option<String>,
+    enrich: Option<bool>,
+    negative: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TransitionFile {
+    #[serde(rename = "type")]
+    kind: Option<Spanned<String>>,
+    /// Seconds
+    duration: Option<Spanned<f64>>,
+    easing: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AnimationFile {
+    loops: Option<u32>,
+    max_fps: Option<Spanned<u32>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutputFile {
+    monitor: String,
+    source: Option<Spanned<String>>,
+    mode: Option<Spanned<String>>,
+    background: Option<Spanned<String>>,
+    interval: Option<Spanned<IntervalFile>>,
+    order: Option<Spanned<String>>,
+}
+
+/// `interval = 3600` or `interval = "every day at 07:00"`
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "seconds or a schedule like \"every day at 07:00\"")]
+enum IntervalFile {
+    Seconds(u64),
+    Schedule(String),
+}
+
+impl IntervalFile {
+    fn parse(&self) -> Result<Schedule, String> {
+        match self {
+            IntervalFile::Seconds(seconds) => seconds.to_string().parse(),
+            IntervalFile::Schedule(schedule) => schedule.parse(),
+        }
+    }
+}
+
+/// `backend = "gemini"` or a fallback chain like `backend = ["gemini", "local"]`
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a backend name or a list of backend names")]
+enum BackendFile {
+    One(String),
+    Chain(Vec<String>),
+}
+
+impl BackendFile {
+    fn names(&self) -> &[String] {
+        match self {
+            BackendFile::One(name) => std::slice::from_ref(name),
+            BackendFile::Chain(names) => names,
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/paber/config.toml`, falling back to `~/.config/paber/config.toml`.
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("paber").join("config.toml"))
+}
+
+pub fn load_config(path: &Path) -> Result<Config, Vec<ConfigError>> {
+    let text = fs::read_to_string(path).map_err(|e| vec![ConfigError {
+        location: None,
+        message: format!("Failed to read {}: {e}", path.display()),
+    }])?;
+    parse_config(&text)
+}
+
+pub fn parse_config(text: &str) -> Result<Config, Vec<ConfigError>> {
+    let file: ConfigFile = toml::from_str(text).map_err(|e| vec![ConfigError {
+        location: e.span().map(|span| location(text, span.start)),
+        message: e.message().trim().to_string(),
+    }])?;
+
+    let mut errors = Vec::new();
+    let mut check = |value: &Option<Spanned<String>>, parse: &dyn Fn(&str) -> Result<(), String>| {
+        if let Some(value) = value
+            && let Err(message) = parse(value.get_ref())
+        {
+            errors.push(error_at(text, value.span(), message));
+        }
+    };
+
+    check(&file.defaults.mode, &|v| v.parse::<ScaleMode>().map(|_| ()));
+    check(&file.defaults.background, &|v| Fill::parse(v).map(|_| ()));
+    let gemini = &file.generator.gemini;
+    check(&gemini.base_url, &|v| reqwest::Url::parse(v).map(|_| ()).map_err(|e| format!("invalid base_url '{v}': {e}")));
+    check(&gemini.proxy, &|v| reqwest::Proxy::all(v).map(|_| ()).map_err(|e| format!("invalid proxy '{v}': {e}")));
+    check(&gemini.aspect_ratio, &|v| match ASPECT_RATIOS.contains(&v) {
+        true => Ok(()),
+        false => Err(format!("unsupported aspect_ratio '{v}' (expected one of {})", ASPECT_RATIOS.join(", "))),
+    });
+    check(&file.cycle.order, &|v| v.parse::<Order>().map(|_| ()));
+    for pattern in file.cycle.include.iter().chain(&file.cycle.exclude) {
+        check(&Some(pattern.clone()), &|v| parse_glob(v).map(|_| ()));
+    }
+    check(&file.transition.kind, &|v| v.parse::<TransitionKind>().map(|_| ()));
+    check(&file.transition.easing, &|v| v.parse::<Easing>().map(|_| ()));
+    for output in &file.outputs {
+        check(&output.source, &|v| Source::parse(&expand_home(v)).map(|_| ()));
+        check(&output.mode, &|v| v.parse::<ScaleMode>().map(|_| ()));
+        check(&output.background, &|v| Fill::parse(v).map(|_| ()));
+        check(&output.order, &|v| v.parse::<Order>().map(|_| ()));
+    }
+    let intervals = [&file.cycle.interval, &file.generator.interval].into_iter()
+        .chain(file.outputs.iter().map(|output| &output.interval));
+    for interval in intervals.flatten() {
+        if let Err(message) = interval.get_ref().parse() {
+            errors.push(error_at(text, interval.span(), message));
+        }
+    }
+    if let Some(backend) = &file.generator.backend {
+        let names = backend.get_ref().names();
+        if names.is_empty() {
+            errors.push(error_at(text, backend.span(), "at least one backend is needed".to_string()));
+        }
+        for name in names.iter().filter(|name| !backend_names().contains(&name.as_str())) {
+            let message = format!("unknown generator backend '{name}' (expected one of {})", backend_names().join(", "));
+            errors.push(error_at(text, backend.span(), message));
+        }
+    }
+    if let Some(timeout) = &gemini.timeout
+        && !Duration::try_from_secs_f64(*timeout.get_ref()).is_ok_and(|timeout| !timeout.is_zero())
+    {
+        errors.push(error_at(text, timeout.span(), format!("invalid timeout {}", timeout.get_ref())));
+    }
+    if let Some(count) = &gemini.candidate_count
+        && *count.get_ref() == 0
+    {
+        errors.push(error_at(text, count.span(), "candidate_count has to be at least 1".to_string()));
+    }
+    if let Some(temperature) = &gemini.temperature
+        && !(0.0..=2.0).contains(temperature.get_ref())
+    {
+        errors.push(error_at(text, temperature.span(), format!("temperature {} is outside 0 to 2", temperature.get_ref())));
+    }
+    if let Some(duration) = &file.transition.duration
+        && Duration::try_from_secs_f64(*duration.get_ref()).is_err()
+    {
+        errors.push(error_at(text, duration.span(), format!("invalid duration {}", duration.get_ref())));
+    }
+    if let Some(max_fps) = &file.animation.max_fps
+        && *max_fps.get_ref() == 0
+    {
+        errors.push(error_at(text, max_fps.span(), "max_fps has to be at least 1".to_string()));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // Everything was validated above, so the conversions below can't fail
+    let mode = |v: &Option<Spanned<String>>| v.as_ref().and_then(|v| v.get_ref().parse().ok());
+    let fill = |v: &Option<Spanned<String>>| v.as_ref().and_then(|v| Fill::parse(v.get_ref()).ok());
+    let order = |v: &Option<Spanned<String>>| v.as_ref().and_then(|v| v.get_ref().parse().ok());
+    let interval = |v: &Option<Spanned<IntervalFile>>| v.as_ref().and_then(|v| v.get_ref().parse().ok());
+
+    Ok(Config {
+        home: file.home.as_deref().map(expand_home),
+        mode: mode(&file.defaults.mode),
+        background: fill(&file.defaults.background),
+        interval: interval(&file.cycle.interval),
+        order: order(&file.cycle.order),
+        scan: ScanOptions {
+            // A depth limit only makes sense when scanning recursively
+            recursive: file.cycle.recursive.unwrap_or(file.cycle.max_depth.is_some()),
+            max_depth: file.cycle.max_depth,
+            include: file.cycle.include.iter().filter_map(|v| parse_glob(v.get_ref()).ok()).collect(),
+            exclude: file.cycle.exclude.iter().filter_map(|v| parse_glob(v.get_ref()).ok()).collect(),
+            follow_symlinks: file.cycle.follow_symlinks.unwrap_or(false),
+            hidden: file.cycle.hidden.unwrap_or(false),
+        },
+        generator: GeneratorConfig {
+            backends: file.generator.backend
+                .map_or(GeneratorConfig::default().backends, |b| b.into_inner().names().to_vec()),
+            api_key: file.generator.api_key,
+            interval: interval(&file.generator.interval),
+            prefetch: file.generator.prefetch.unwrap_or(GeneratorConfig::default().prefetch),
+            seed: file.generator.seed,
+            gemini: {
+                let gemini = file.generator.gemini;
+                let default = GeminiConfig::default();
+                GeminiConfig {
+                    model: gemini.model.unwrap_or(default.model),
+                    base_url: gemini.base_url.map_or(default.base_url, |v| v.into_inner()),
+                    timeout: gemini.timeout.map_or(default.timeout, |v| Duration::from_secs_f64(v.into_inner())),
+                    proxy: gemini.proxy.map(|v| v.into_inner()),
+                    aspect_ratio: gemini.aspect_ratio.map(|v| v.into_inner()),
+                    image_size: gemini.image_size,
+                    candidate_count: gemini.candidate_count.map(|v| v.into_inner()),
+                    temperature: gemini.temperature.map(|v| v.into_inner()),
+                    safety_settings: gemini.safety_settings,
+                }
+            },
+        },
+        prompt: PromptConfig {
+            text: file.prompt.text,
+            enrich: file.prompt.enrich.unwrap_or(true),
+            negative: file.prompt.negative,
+        },
+        transition: TransitionSettings {
+            kind: file.transition.kind.and_then(|v| v.get_ref().parse().ok()).unwrap_or_default(),
+            duration: file.transition.duration
+                .map_or(TransitionSettings::default().duration, |v| Duration::from_secs_f64(v.into_inner())),
+            easing: file.transition.easing.and_then(|v| v.get_ref().parse().ok()).unwrap_or_default(),
+        },
+        animation: AnimationSettings {
+            loops: file.animation.loops.unwrap_or(AnimationSettings::default().loops),
+            max_fps: file.animation.max_fps.map_or(AnimationSettings::default().max_fps, |v| v.into_inner()),
+        },
+        outputs: file.outputs.iter().map(|output| OutputConfig {
+            monitor: output.monitor.clone(),
+            source: output.source.as_ref().and_then(|v| Source::parse(&expand_home(v.get_ref())).ok()),
+            mode: mode(&output.mode),
+            background: fill(&output.background),
+            interval: interval(&output.interval),
+            order: order(&output.order),
+        }).collect(),
+    })
+}
+
+/// Expands a leading `~/` to the home directory.
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{home}/{rest}"),
+        _ => path.to_string(),
+    }
+}
+
+fn error_at(text: &str, span: Range<usize>, message: String) -> ConfigError {
+    ConfigError { location: Some(location(text, span.start)), message }
+}
+
+fn location(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(text: &str) -> Vec<((usize, usize), String)> {
+        parse_config(text).unwrap_err().into_iter()
+            .map(|error| (error.location.expect("the error has a location"), error.message))
+            .collect()
+    }
+
+    #[test]
+    fn parses_a_valid_config() {
+        let config = parse_config(r##"
+[defaults]
+mode = "fit"
+background = "linear-gradient(black, white)"
+
+[cycle]
+interval = "30m"
+
+[generator]
+backend = ["gemini", "local"]
+
+[[output]]
+monitor = "DP-1"
+source = "#1e90ff"
+"##).unwrap();
+        assert_eq!(config.mode, Some(ScaleMode::Fit));
+        assert_eq!(config.interval, Some(Schedule::Every(Duration::from_secs(30 * 60))));
+        assert_eq!(config.generator.backends, ["gemini", "local"]);
+        assert_eq!(config.outputs.len(), 1);
+        assert_eq!(config.outputs[0].monitor, "DP-1");
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = parse_config("").unwrap();
+        assert_eq!(config.mode, None);
+        assert_eq!(config.generator.backends, ["gemini"]);
+        assert!(config.outputs.is_empty());
+    }
+
+    #[test]
+    fn syntax_errors_point_at_their_line() {
+        let found = errors("[defaults]\nmode = \"fit\"\nbackground = \n");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0.0, 3);
+    }
+
+    #[test]
+    fn unknown_fields_point_at_their_line() {
+        let found = errors("[cycle]\norder = \"name\"\nspeed = 3\n");
+        assert_eq!(found[0].0, (3, 1));
+        assert!(found[0].1.contains("unknown field `speed`"), "{}", found[0].1);
+    }
+
+    #[test]
+    fn every_invalid_value_is_reported_at_its_value() {
+        let found = errors("[defaults]\nmode = \"zoom\"\n\n[[output]]\nmonitor = \"DP-1\"\nbackground = \"notacolour\"\ninterval = \"soon\"\n");
+        let locations: Vec<(usize, usize)> = found.iter().map(|(location, _)| *location).collect();
+        assert_eq!(locations, [(2, 8), (6, 14), (7, 12)]);
+        assert!(found[0].1.contains("unknown scaling mode 'zoom'"), "{}", found[0].1);
+    }
+
+    #[test]
+    fn locations_start_at_one() {
+        assert_eq!(location("abc", 0), (1, 1));
+        assert_eq!(location("abc\ndef", 5), (2, 2));
+        assert_eq!(location("abc\n", 100), (2, 1));
+    }
+}
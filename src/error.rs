@@ -0,0 +1,72 @@
+use std::{error, fmt, path::PathBuf};
+
+use wayland_client::{ConnectError, DispatchError};
+
+/// Something that went wrong while putting a wallpaper on screen.
+#[derive(Debug)]
+pub enum PaberError {
+    /// The Wayland connection couldn't be set up or broke
+    Wayland(String),
+    /// The compositor lacks a protocol paber can't do without
+    MissingGlobal(&'static str),
+    /// An image couldn't be opened or decoded
+    Image { path: PathBuf, message: String },
+    /// A rendered image couldn't be saved
+    Write { path: PathBuf, message: String },
+    /// Scaling an image panicked
+    Scaling(PathBuf),
+    /// A cycle source has nothing to show
+    NoImages(String),
+    /// The output hasn't been given a size by the compositor
+    EmptySize,
+    /// Creating or mapping a shared memory buffer failed
+    Buffer(nix::Error),
+    /// Generated images need somewhere to be stored
+    NoHome,
+    /// The image backend failed to generate an image
+    Generation(anyhow::Error),
+    /// A monitor selector doesn't match any monitor
+    Monitor(String),
+    /// The event loop or one of its sources couldn't be set up
+    EventLoop(String),
+}
+
+impl fmt::Display for PaberError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaberError::Wayland(message) => write!(f, "Wayland error: {message}"),
+            PaberError::MissingGlobal(interface) => write!(f, "the compositor does not support '{interface}'"),
+            PaberError::Image { path, message } => write!(f, "failed to open {}: {message}", path.display()),
+            PaberError::Write { path, message } => write!(f, "failed to write {}: {message}", path.display()),
+            PaberError::Scaling(path) => write!(f, "failed to scale {}", path.display()),
+            PaberError::NoImages(dirs) => write!(f, "no images found in {dirs}"),
+            PaberError::EmptySize => write!(f, "the output has no size"),
+            PaberError::Buffer(e) => write!(f, "failed to allocate a buffer: {e}"),
+            PaberError::NoHome => write!(f, "PABER_HOME is not set and no home is configured"),
+            PaberError::Generation(e) => write!(f, "failed to generate wallpaper: {e:#}"),
+            PaberError::Monitor(message) => write!(f, "{message}"),
+            PaberError::EventLoop(message) => write!(f, "event loop error: {message}"),
+        }
+    }
+}
+
+impl error::Error for PaberError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PaberError::Buffer(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConnectError> for PaberError {
+    fn from(e: ConnectError) -> Self {
+        PaberError::Wayland(e.to_string())
+    }
+}
+
+impl From<DispatchError> for PaberError {
+    fn from(e: DispatchError) -> Self {
+        PaberError::Wayland(e.to_string())
+    }
+}
@@ -1,63 +1,124 @@
 use anyhow::{Context, Result};
 use base64::prelude::*;
-use reqwest::blocking::Client; 
+use reqwest::{Proxy, blocking::Client}; 
 use serde::Deserialize;
-use serde_json::json;
-use std::fs;
+use serde_json::{Value, json};
 use std::env;
 
+use crate::{backend::{GeneratedImage, GenerationRequest, ImageGenerator, ImageMetadata}, config::{ASPECT_RATIOS, GeminiConfig, GeneratorConfig}};
+
 #[derive(Debug, Deserialize)]
 struct GeminiResponse { candidates: Option<Vec<Candidate>> }
+// Candidates blocked by the safety settings come without content
 #[derive(Debug, Deserialize)]
-struct Candidate { content: Content }
-#[derive(Debug, Deserialize)]
-struct Content { parts: Vec<Part> }
+struct Candidate { #[serde(default)] content: Content }
+#[derive(Debug, Deserialize, Default)]
+struct Content { #[serde(default)] parts: Vec<Part> }
 #[derive(Debug, Deserialize)]
 struct Part {
     #[serde(rename = "inlineData")]
     inline_data: Option<InlineData>,
 }
 #[derive(Debug, Deserialize)]
-struct InlineData { data: String }
+struct InlineData {
+    data: String,
+    #[serde(rename = "mimeType")]
+    mime_type: Option<String>,
+}
 
-pub struct WallpaperTool {
+pub struct GeminiGenerator {
     client: Client,
     api_key: String,
+    settings: GeminiConfig,
 }
 
-impl WallpaperTool {
-    pub fn new() -> Result<Self> {
-        let api_key = env::var("GEMINI_API_KEY")
-            .context("GEMINI_API_KEY must be set")?;
+impl GeminiGenerator {
+    /// Uses GEMINI_API_KEY if it is set, otherwise the configured key.
+    pub fn new(config: &GeneratorConfig) -> Result<Self> {
+        let api_key = env::var("GEMINI_API_KEY").ok().or(config.api_key.clone())
+            .context("GEMINI_API_KEY must be set or api_key configured")?;
+        let settings = config.gemini.clone();
+
+        let mut client = Client::builder().timeout(settings.timeout);
+        if let Some(proxy) = &settings.proxy {
+            client = client.proxy(Proxy::all(proxy).context("Invalid proxy")?);
+        }
         
         Ok(Self {
-            client: Client::new(),
+            client: client.build().context("Failed to set up the HTTP client")?,
             api_key,
+            settings,
         })
     }
 
-    pub fn generate_online(&self, prompt: &str, output_path: &str) -> Result<()> {
-        println!("Sending prompt to Google AI Studio...");
+    /// The `generationConfig` of a request.
+    fn generation_config(&self, request: &GenerationRequest) -> Value {
+        let settings = &self.settings;
+        let mut config = json!({
+            "responseModalities": ["IMAGE"] 
+        });
+        if let Some(seed) = request.seed {
+            config["seed"] = json!(seed);
+        }
+        if let Some(count) = settings.candidate_count {
+            config["candidateCount"] = json!(count);
+        }
+        if let Some(temperature) = settings.temperature {
+            config["temperature"] = json!(temperature);
+        }
+        let mut image_config = json!({});
+        // A configured aspect ratio wins over the shape of the output
+        let aspect_ratio = settings.aspect_ratio.as_deref().or_else(|| request.size.map(nearest_aspect_ratio));
+        if let Some(aspect_ratio) = aspect_ratio {
+            image_config["aspectRatio"] = json!(aspect_ratio);
+        }
+        if let Some(image_size) = &settings.image_size {
+            image_config["imageSize"] = json!(image_size);
+        }
+        if image_config != json!({}) {
+            config["imageConfig"] = image_config;
+        }
+        config
+    }
 
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash-image:generateContent?key={}", 
-            self.api_key
-        );
+    /// The endpoint of the model. The key goes in a header, URLs end up in error
+    /// messages and access logs.
+    fn url(&self) -> String {
+        format!("{}/models/{}:generateContent", self.settings.base_url.trim_end_matches('/'), self.settings.model)
+    }
 
-        let payload = json!({
+    /// The JSON body of a request.
+    fn payload(&self, request: &GenerationRequest) -> Value {
+        // Gemini has no negative prompt, so it is part of the text
+        let text = match &request.negative_prompt {
+            Some(negative) => format!("{}. Avoid: {negative}", request.prompt),
+            None => request.prompt.clone(),
+        };
+        let mut payload = json!({
             "contents": [{
-                "parts": [{ "text": prompt }]
+                "parts": [{ "text": text }]
             }],
-            "generationConfig": {
-                "responseModalities": ["IMAGE"] 
-            }
+            "generationConfig": self.generation_config(request)
         });
+        if !self.settings.safety_settings.is_empty() {
+            payload["safetySettings"] = self.settings.safety_settings.iter()
+                .map(|(category, threshold)| json!({ "category": category, "threshold": threshold }))
+                .collect();
+        }
+        payload
+    }
+}
+
+impl ImageGenerator for GeminiGenerator {
+    fn generate(&self, request: &GenerationRequest) -> Result<GeneratedImage> {
+        println!("Sending prompt to {}...", self.settings.model);
 
-        let res = self.client.post(&url)
+        let res = self.client.post(self.url())
             .header("Content-Type", "application/json")
-            .json(&payload)
+            .header("x-goog-api-key", &self.api_key)
+            .json(&self.payload(request))
             .send()
-            .context("Failed to contact Google API")?;
+            .context("Failed to contact the Gemini API")?;
 
         if !res.status().is_success() {
             anyhow::bail!("API Error: {}", res.text()?);
@@ -66,22 +127,176 @@ impl WallpaperTool {
         let response_body: GeminiResponse = res.json()
             .context("Failed to parse JSON")?;
 
-        if let Some(candidates) = response_body.candidates {
-            if let Some(first) = candidates.first() {
-                for part in &first.content.parts {
-                    if let Some(inline_data) = &part.inline_data {
-                        println!("Image received! Decoding...");
-                        
-                        let clean_b64 = inline_data.data.replace('\n', "");
-                        let image_bytes = BASE64_STANDARD.decode(&clean_b64)
-                            .context("Failed to decode base64 image data")?;
-                        fs::write(output_path, image_bytes)?;
-                        println!("Image saved to {}", output_path);
-                        return Ok(());
-                    }
+        // With several candidates, the first one holding an image wins
+        for candidate in response_body.candidates.iter().flatten() {
+            for part in &candidate.content.parts {
+                if let Some(inline_data) = &part.inline_data {
+                    println!("Image received! Decoding...");
+                    
+                    let clean_b64 = inline_data.data.replace('\n', "");
+                    let bytes = BASE64_STANDARD.decode(&clean_b64)
+                        .context("Failed to decode base64 image data")?;
+                    let extension = match inline_data.mime_type.as_deref() {
+                        Some("image/jpeg") => "jpg",
+                        Some("image/webp") => "webp",
+                        _ => "png",
+                    };
+                    let metadata = ImageMetadata { model: Some(self.settings.model.clone()), seed: request.seed, ..Default::default() };
+                    return Ok(GeneratedImage { bytes, extension, metadata });
                 }
             }
         }
         anyhow::bail!("No image found in response");
     }
 }
+
+/// The supported aspect ratio closest to `width`x`height`, so the image needs the least cropping.
+fn nearest_aspect_ratio((width, height): (u32, u32)) -> &'static str {
+    let ratio = |width: f64, height: f64| (width / height).ln();
+    let wanted = ratio(width.max(1) as f64, height.max(1) as f64);
+    let distance = |aspect_ratio: &&str| {
+        let (width, height) = aspect_ratio.split_once(':').expect("aspect ratios are width:height");
+        (ratio(width.parse().unwrap(), height.parse().unwrap()) - wanted).abs()
+    };
+    ASPECT_RATIOS.iter().min_by(|a, b| distance(a).total_cmp(&distance(b))).expect("there are aspect ratios")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeMap, io::{BufRead, BufReader, Read, Write}, net::TcpListener, thread};
+
+    fn generator(settings: GeminiConfig) -> GeminiGenerator {
+        let client = Client::builder().no_proxy().build().unwrap();
+        GeminiGenerator { client, api_key: "test-key".to_string(), settings }
+    }
+
+    fn request() -> GenerationRequest {
+        GenerationRequest { prompt: "a lighthouse".to_string(), size: Some((2560, 1080)), seed: Some(42), negative_prompt: None }
+    }
+
+    #[test]
+    fn builds_the_url_from_the_base_url_and_model() {
+        let settings = GeminiConfig { base_url: "http://localhost:1234/v1beta/".to_string(), model: "imagen".to_string(), ..Default::default() };
+        assert_eq!(generator(settings).url(), "http://localhost:1234/v1beta/models/imagen:generateContent");
+        assert_eq!(
+            generator(GeminiConfig::default()).url(),
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash-image:generateContent",
+        );
+    }
+
+    #[test]
+    fn leaves_unset_settings_out_of_the_body() {
+        let request = GenerationRequest { prompt: "a lighthouse".to_string(), ..Default::default() };
+        assert_eq!(generator(GeminiConfig::default()).payload(&request), json!({
+            "contents": [{ "parts": [{ "text": "a lighthouse" }] }],
+            "generationConfig": { "responseModalities": ["IMAGE"] },
+        }));
+    }
+
+    #[test]
+    fn puts_the_settings_in_the_body() {
+        let settings = GeminiConfig {
+            candidate_count: Some(2),
+            temperature: Some(0.5),
+            image_size: Some("2K".to_string()),
+            safety_settings: BTreeMap::from([("HARM_CATEGORY_HARASSMENT".to_string(), "BLOCK_NONE".to_string())]),
+            ..Default::default()
+        };
+        let request = GenerationRequest { negative_prompt: Some("people".to_string()), ..request() };
+        assert_eq!(generator(settings).payload(&request), json!({
+            "contents": [{ "parts": [{ "text": "a lighthouse. Avoid: people" }] }],
+            "generationConfig": {
+                "responseModalities": ["IMAGE"],
+                "seed": 42,
+                "candidateCount": 2,
+                "temperature": 0.5,
+                "imageConfig": { "aspectRatio": "21:9", "imageSize": "2K" },
+            },
+            "safetySettings": [{ "category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_NONE" }],
+        }));
+    }
+
+    #[test]
+    fn a_configured_aspect_ratio_wins_over_the_output_shape() {
+        let settings = GeminiConfig { aspect_ratio: Some("1:1".to_string()), ..Default::default() };
+        let config = generator(settings).generation_config(&request());
+        assert_eq!(config["imageConfig"], json!({ "aspectRatio": "1:1" }));
+    }
+
+    #[test]
+    fn picks_the_nearest_aspect_ratio() {
+        let cases = [
+            ((1920, 1080), "16:9"),
+            ((3840, 2160), "16:9"),
+            ((2560, 1080), "21:9"),
+            ((3440, 1440), "21:9"),
+            ((5120, 1440), "21:9"),
+            ((1920, 1200), "3:2"),
+            ((1600, 1200), "4:3"),
+            ((1280, 1024), "5:4"),
+            ((1024, 1024), "1:1"),
+            ((1080, 1920), "9:16"),
+            ((1200, 1920), "2:3"),
+            ((0, 0), "1:1"),
+        ];
+        for (size, aspect_ratio) in cases {
+            assert_eq!(nearest_aspect_ratio(size), aspect_ratio, "{size:?}");
+        }
+    }
+
+    /// The request line, lowercased headers and JSON body of a request.
+    type Received = (String, Vec<(String, String)>, Value);
+
+    /// Answers one request with `body` and returns what it got.
+    fn serve_once(listener: TcpListener, body: String) -> thread::JoinHandle<Received> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let Some((name, value)) = line.trim_end().split_once(':') else { break };
+                headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+            }
+            let length = headers.iter().find(|(name, _)| name == "content-length")
+                .map_or(0, |(_, value)| value.parse().unwrap());
+            let mut request_body = vec![0; length];
+            reader.read_exact(&mut request_body).unwrap();
+
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()).unwrap();
+            (request_line.trim_end().to_string(), headers, serde_json::from_slice(&request_body).unwrap())
+        })
+    }
+
+    #[test]
+    fn sends_the_request_to_the_base_url() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/v1beta", listener.local_addr().unwrap());
+        let image = b"not really a png";
+        let response = json!({
+            "candidates": [
+                {},
+                { "content": { "parts": [{ "inlineData": { "mimeType": "image/webp", "data": BASE64_STANDARD.encode(image) } }] } },
+            ]
+        });
+        let server = serve_once(listener, response.to_string());
+
+        let generator = generator(GeminiConfig { base_url, model: "imagen".to_string(), ..Default::default() });
+        let generated = generator.generate(&request()).unwrap();
+        assert_eq!(generated.bytes, image);
+        assert_eq!(generated.extension, "webp");
+        assert_eq!(generated.metadata.model.as_deref(), Some("imagen"));
+        assert_eq!(generated.metadata.seed, Some(42));
+
+        let (request_line, headers, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /v1beta/models/imagen:generateContent HTTP/1.1");
+        assert!(headers.contains(&("x-goog-api-key".to_string(), "test-key".to_string())));
+        assert!(!request_line.contains("test-key"));
+        assert_eq!(body, generator.payload(&request()));
+    }
+}
@@ -0,0 +1,359 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, fs, path::{Path, PathBuf}, sync::{Arc, Mutex, PoisonError, mpsc}, thread};
+
+use calloop::channel::Sender;
+use uuid::Uuid;
+
+use crate::{backend::{self, GenerationRequest}, config::GeneratorConfig, error::PaberError, ipc::GenerationStatus, source::Source};
+
+/// What the worker thread tells the event loop.
+pub enum GenerationEvent {
+    /// The worker started on a job
+    Started(u64),
+    Finished { id: u64, result: Result<String, PaberError> },
+}
+
+struct Job {
+    id: u64,
+    /// Request with the enriched prompt
+    request: GenerationRequest,
+    home: Option<PathBuf>,
+    generator: GeneratorConfig,
+}
+
+struct PendingJob {
+    id: u64,
+    key: GenerationKey,
+}
+
+/// What an image is generated for. Wallpapers with the same prompt and aspect ratio share images.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GenerationKey {
+    /// Prompt of the generated source, before enrichment
+    pub user_prompt: Option<String>,
+    /// Width per height in hundredths, so 1920x1080 and 1366x768 count as the same.
+    /// None while the output has no size
+    pub aspect: Option<u32>,
+}
+
+impl GenerationKey {
+    pub fn new(user_prompt: Option<String>, (width, height): (u32, u32)) -> Self {
+        let aspect = (width > 0 && height > 0).then(|| (width as f64 / height as f64 * 100.0).round() as u32);
+        GenerationKey { user_prompt, aspect }
+    }
+}
+
+/// Generates images one at a time on a worker thread, keeping the event loop free,
+/// and holds the images generated ahead of their switch.
+pub struct Generator {
+    jobs: mpsc::Sender<Job>,
+    /// Jobs given to the worker that haven't finished, oldest first
+    pending: Vec<PendingJob>,
+    /// The job the worker is busy with
+    running: Option<u64>,
+    /// Prefetched images, oldest first
+    ready: HashMap<GenerationKey, VecDeque<String>>,
+    /// Jobs the worker skips when it gets to them
+    cancelled: Arc<Mutex<HashSet<u64>>>,
+    cancelled_total: usize,
+    next_id: u64,
+}
+
+impl Generator {
+    /// Starts the worker thread, which reports to `events`.
+    pub fn new(events: Sender<GenerationEvent>) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let generator = Generator::with_jobs(jobs);
+        let skip = generator.cancelled.clone();
+        thread::spawn(move || work(queue, skip, events));
+        generator
+    }
+
+    /// The queue without a worker, whatever receives `jobs` runs them.
+    fn with_jobs(jobs: mpsc::Sender<Job>) -> Self {
+        Generator {
+            jobs,
+            pending: Vec::new(),
+            running: None,
+            ready: HashMap::new(),
+            cancelled: Arc::new(Mutex::new(HashSet::new())),
+            cancelled_total: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Queues an image for `request`, whose prompt is enriched from the prompt of `key`.
+    pub fn queue(&mut self, request: GenerationRequest, key: GenerationKey, home: Option<PathBuf>, generator: GeneratorConfig) {
+        self.next_id += 1;
+        let id = self.next_id;
+        if self.jobs.send(Job { id, request, home, generator }).is_err() {
+            eprintln!("Error: the generator thread has stopped");
+            return;
+        }
+        self.pending.push(PendingJob { id, key });
+    }
+
+    /// Jobs for `key` that haven't finished.
+    pub fn queued_for(&self, key: &GenerationKey) -> usize {
+        self.pending.iter().filter(|job| job.key == *key).count()
+    }
+
+    pub fn ready_for(&self, key: &GenerationKey) -> usize {
+        self.ready.get(key).map_or(0, VecDeque::len)
+    }
+
+    /// How many upcoming switches of `key` are covered by images that are ready or queued,
+    /// and how many more to queue so `prefetch` are. The first queued job goes to the
+    /// wallpapers that are `waiting`.
+    pub fn to_prefetch(&self, key: &GenerationKey, prefetch: usize, waiting: bool) -> (usize, usize) {
+        let ahead = (self.ready_for(key) + self.queued_for(key)).saturating_sub(waiting as usize);
+        (ahead, prefetch.saturating_sub(ahead))
+    }
+
+    /// The oldest prefetched image for `key`.
+    pub fn take_ready(&mut self, key: &GenerationKey) -> Option<String> {
+        self.ready.get_mut(key)?.pop_front()
+    }
+
+    /// Keeps an image nobody was waiting for until the next switch.
+    pub fn store_ready(&mut self, key: GenerationKey, path: String) {
+        self.ready.entry(key).or_default().push_back(path);
+    }
+
+    /// Updates the queue. Returns the key and result of a job that finished,
+    /// unless it was cancelled while running.
+    pub fn handle(&mut self, event: GenerationEvent) -> Option<(GenerationKey, Result<String, PaberError>)> {
+        match event {
+            GenerationEvent::Started(id) => {
+                self.running = Some(id);
+                None
+            }
+            GenerationEvent::Finished { id, result } => {
+                self.running = None;
+                let position = self.pending.iter().position(|job| job.id == id)?;
+                Some((self.pending.remove(position).key, result))
+            }
+        }
+    }
+
+    /// Cancels the jobs and drops the prefetched images no wallpaper can show anymore.
+    /// A job that is already running finishes, but its image is discarded.
+    pub fn retain(&mut self, wanted: impl Fn(&GenerationKey) -> bool) {
+        let mut cancelled = self.cancelled.lock().unwrap_or_else(PoisonError::into_inner);
+        self.pending.retain(|job| {
+            if wanted(&job.key) {
+                return true;
+            }
+            println!("Cancelled generating {}", Source::Generated(job.key.user_prompt.clone()));
+            if self.running != Some(job.id) {
+                cancelled.insert(job.id);
+            }
+            self.cancelled_total += 1;
+            false
+        });
+        self.ready.retain(|key, _| wanted(key));
+    }
+
+    pub fn status(&self) -> GenerationStatus {
+        GenerationStatus {
+            running: self.running
+                .and_then(|id| self.pending.iter().find(|job| job.id == id))
+                .map(|job| Source::Generated(job.key.user_prompt.clone()).to_string()),
+            queued: self.pending.len(),
+            ready: self.ready.values().map(VecDeque::len).sum(),
+            cancelled: self.cancelled_total,
+        }
+    }
+}
+
+/// Runs the jobs in order until the event loop is gone.
+fn work(queue: mpsc::Receiver<Job>, cancelled: Arc<Mutex<HashSet<u64>>>, events: Sender<GenerationEvent>) {
+    for job in queue {
+        if cancelled.lock().unwrap_or_else(PoisonError::into_inner).remove(&job.id) {
+            continue;
+        }
+        if events.send(GenerationEvent::Started(job.id)).is_err() {
+            return;
+        }
+        let result = generate_img(&job.request, job.home.as_deref(), &job.generator);
+        if events.send(GenerationEvent::Finished { id: job.id, result }).is_err() {
+            return;
+        }
+    }
+}
+
+/// Generates an image for `request` into `home`/generated and returns its path.
+pub fn generate_img(request: &GenerationRequest, home: Option<&Path>, generator: &GeneratorConfig) -> Result<String, PaberError> {
+    let home = home.ok_or(PaberError::NoHome)?;
+    let image = backend::generate(request, generator).map_err(PaberError::Generation)?;
+
+    let dir = home.join("generated");
+    let output = dir.join(format!("generated{}.{}", Uuid::new_v4(), image.extension));
+    let write_error = |e: std::io::Error| PaberError::Write { path: output.clone(), message: e.to_string() };
+    fs::create_dir_all(&dir).map_err(write_error)?;
+    fs::write(&output, &image.bytes).map_err(write_error)?;
+
+    let metadata = &image.metadata;
+    let details: Vec<String> = metadata.model.iter().cloned()
+        .chain(metadata.seed.map(|seed| format!("seed {seed}")))
+        .collect();
+    match details.is_empty() {
+        true => println!("Generated {} with {}", output.display(), metadata.backend),
+        false => println!("Generated {} with {} ({})", output.display(), metadata.backend, details.join(", ")),
+    }
+    Ok(output.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(prompt: &str) -> GenerationKey {
+        GenerationKey::new(Some(prompt.to_string()), (1920, 1080))
+    }
+
+    #[test]
+    fn keys_by_aspect_ratio_in_hundredths() {
+        let cases = [
+            ((1920, 1080), Some(178)),
+            ((2560, 1440), Some(178)),
+            ((1366, 768), Some(178)),
+            ((2560, 1080), Some(237)),
+            ((3440, 1440), Some(239)),
+            ((1080, 1920), Some(56)),
+            ((1024, 1024), Some(100)),
+            ((0, 1080), None),
+        ];
+        for (size, aspect) in cases {
+            assert_eq!(GenerationKey::new(None, size).aspect, aspect, "{size:?}");
+        }
+    }
+
+    #[test]
+    fn outputs_with_the_same_prompt_and_shape_share_a_key() {
+        let sea = |size| GenerationKey::new(Some("sea".to_string()), size);
+        assert_eq!(sea((1920, 1080)), sea((2560, 1440)));
+        assert_ne!(sea((1920, 1080)), sea((1080, 1920)));
+        assert_ne!(sea((1920, 1080)), GenerationKey::new(None, (1920, 1080)));
+        let keys: HashSet<GenerationKey> = [sea((1920, 1080)), sea((3840, 2160)), sea((2560, 1080))].into_iter().collect();
+        assert_eq!(keys.len(), 2);
+    }
+
+    /// A generator whose jobs come out of the returned receiver.
+    fn generator() -> (Generator, mpsc::Receiver<Job>) {
+        let (jobs, queue) = mpsc::channel();
+        (Generator::with_jobs(jobs), queue)
+    }
+
+    fn queue(generator: &mut Generator, prompt: &str) {
+        let request = GenerationRequest { prompt: format!("enriched {prompt}"), ..Default::default() };
+        generator.queue(request, key(prompt), None, GeneratorConfig::default());
+    }
+
+    fn finish(generator: &mut Generator, id: u64) -> Option<(GenerationKey, Result<String, PaberError>)> {
+        generator.handle(GenerationEvent::Finished { id, result: Ok(format!("image{id}.png")) })
+    }
+
+    fn cancelled(generator: &Generator) -> Vec<u64> {
+        let mut ids: Vec<u64> = generator.cancelled.lock().unwrap().iter().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn sends_queued_jobs_to_the_worker() {
+        let (mut generator, jobs) = generator();
+        queue(&mut generator, "sea");
+        queue(&mut generator, "sea");
+        queue(&mut generator, "forest");
+        let sent: Vec<(u64, String)> = jobs.try_iter().map(|job| (job.id, job.request.prompt)).collect();
+        assert_eq!(sent, [(1, "enriched sea".to_string()), (2, "enriched sea".to_string()), (3, "enriched forest".to_string())]);
+        assert_eq!(generator.queued_for(&key("sea")), 2);
+        assert_eq!(generator.queued_for(&key("forest")), 1);
+        assert_eq!(generator.queued_for(&key("desert")), 0);
+        assert_eq!(generator.status().queued, 3);
+    }
+
+    #[test]
+    fn forgets_jobs_when_the_worker_is_gone() {
+        let (mut generator, jobs) = generator();
+        drop(jobs);
+        queue(&mut generator, "sea");
+        assert_eq!(generator.queued_for(&key("sea")), 0);
+    }
+
+    #[test]
+    fn tracks_the_running_job_until_it_finishes() {
+        let (mut generator, _jobs) = generator();
+        queue(&mut generator, "sea");
+        queue(&mut generator, "forest");
+
+        assert!(generator.handle(GenerationEvent::Started(1)).is_none());
+        assert_eq!(generator.status().running.as_deref(), Some("generated:sea"));
+
+        let (finished, result) = finish(&mut generator, 1).unwrap();
+        assert_eq!(finished, key("sea"));
+        assert_eq!(result.unwrap(), "image1.png");
+        assert_eq!(generator.status().running, None);
+        assert_eq!(generator.queued_for(&key("sea")), 0);
+        assert_eq!(generator.queued_for(&key("forest")), 1);
+    }
+
+    #[test]
+    fn hands_out_ready_images_oldest_first() {
+        let (mut generator, _jobs) = generator();
+        generator.store_ready(key("sea"), "first.png".to_string());
+        generator.store_ready(key("sea"), "second.png".to_string());
+        generator.store_ready(key("forest"), "forest.png".to_string());
+        assert_eq!(generator.ready_for(&key("sea")), 2);
+        assert_eq!(generator.status().ready, 3);
+
+        assert_eq!(generator.take_ready(&key("sea")).as_deref(), Some("first.png"));
+        assert_eq!(generator.take_ready(&key("sea")).as_deref(), Some("second.png"));
+        assert_eq!(generator.take_ready(&key("sea")), None);
+        assert_eq!(generator.take_ready(&key("desert")), None);
+        assert_eq!(generator.ready_for(&key("forest")), 1);
+    }
+
+    #[test]
+    fn counts_ready_and_queued_images_towards_the_prefetch() {
+        let (mut generator, _jobs) = generator();
+        assert_eq!(generator.to_prefetch(&key("sea"), 2, false), (0, 2));
+        // The first job goes to the waiting wallpapers, not ahead
+        queue(&mut generator, "sea");
+        assert_eq!(generator.to_prefetch(&key("sea"), 2, true), (0, 2));
+        assert_eq!(generator.to_prefetch(&key("sea"), 2, false), (1, 1));
+        generator.store_ready(key("sea"), "ready.png".to_string());
+        assert_eq!(generator.to_prefetch(&key("sea"), 2, false), (2, 0));
+        queue(&mut generator, "sea");
+        assert_eq!(generator.to_prefetch(&key("sea"), 2, false), (3, 0));
+        assert_eq!(generator.to_prefetch(&key("forest"), 2, false), (0, 2));
+    }
+
+    #[test]
+    fn cancels_jobs_and_images_nobody_wants() {
+        let (mut generator, _jobs) = generator();
+        queue(&mut generator, "sea");
+        queue(&mut generator, "forest");
+        queue(&mut generator, "forest");
+        generator.handle(GenerationEvent::Started(2));
+        generator.store_ready(key("sea"), "sea.png".to_string());
+        generator.store_ready(key("forest"), "forest.png".to_string());
+
+        generator.retain(|key| key.user_prompt.as_deref() == Some("sea"));
+        assert_eq!(generator.queued_for(&key("forest")), 0);
+        assert_eq!(generator.ready_for(&key("forest")), 0);
+        assert_eq!(generator.queued_for(&key("sea")), 1);
+        assert_eq!(generator.ready_for(&key("sea")), 1);
+        // The running job can't be skipped anymore, it finishes unseen
+        assert_eq!(cancelled(&generator), [3]);
+        assert_eq!(generator.status().cancelled, 2);
+        assert!(finish(&mut generator, 2).is_none());
+        assert_eq!(generator.status().running, None);
+
+        generator.retain(|_| false);
+        assert_eq!(cancelled(&generator), [1, 3]);
+        assert_eq!(generator.status().cancelled, 3);
+        assert_eq!(generator.status().queued, 0);
+        assert_eq!(generator.status().ready, 0);
+    }
+}
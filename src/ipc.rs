@@ -0,0 +1,342 @@
+use std::{env, fmt, fs, io::{BufRead, BufReader, ErrorKind, Write}, os::{fd::{AsFd, BorrowedFd}, unix::net::{UnixListener, UnixStream}}, path::PathBuf, sync::mpsc, thread, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use calloop::channel::{self, Channel, Sender};
+use serde::{Deserialize, Serialize};
+
+/// A command sent to the daemon, one JSON object per line.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    /// Shows `source` (same syntax as --output) on the selected monitors
+    Set { monitors: Option<String>, source: String },
+    Next { monitors: Option<String> },
+    Prev { monitors: Option<String> },
+    Pause { monitors: Option<String> },
+    Resume { monitors: Option<String> },
+    Status,
+    /// Re-reads the configuration file and reapplies it
+    Reload,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<OutputStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<GenerationStatus>,
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Response { ok: true, ..Default::default() }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Response { ok: false, error: Some(message.into()), ..Default::default() }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputStatus {
+    /// Number the monitor is selected by
+    pub index: usize,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    /// Logical size
+    pub width: u32,
+    pub height: u32,
+    pub scale: f64,
+    pub source: Option<String>,
+    /// Image currently shown by a cycle source
+    pub current: Option<String>,
+    pub paused: bool,
+    /// The last thing that went wrong, cleared once a wallpaper is shown again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Cycle images that failed to load and are skipped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quarantined: Vec<String>,
+    /// Waiting for a generated image
+    #[serde(default)]
+    pub generating: bool,
+}
+
+/// The lines `paber ctl status` prints for an output.
+impl fmt::Display for OutputStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {}x{}@{} {}{}{}{}",
+            self.index,
+            self.name.as_deref().unwrap_or("unknown"),
+            self.width,
+            self.height,
+            self.scale,
+            self.source.as_deref().unwrap_or("(no source)"),
+            self.current.as_ref().map(|c| format!(" -> {c}")).unwrap_or_default(),
+            if self.paused { " (paused)" } else { "" },
+            if self.generating { " (generating)" } else { "" },
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, "\n   error: {error}")?;
+        }
+        for path in &self.quarantined {
+            write!(f, "\n   quarantined: {path}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The queue of the image generator.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationStatus {
+    /// Source the image being generated is for
+    pub running: Option<String>,
+    /// Jobs that haven't finished, including the running one
+    pub queued: usize,
+    /// Images generated ahead of their switch
+    pub ready: usize,
+    /// Jobs cancelled because no monitor showed their prompt anymore
+    pub cancelled: usize,
+}
+
+/// `$XDG_RUNTIME_DIR/paber.sock`
+pub fn socket_path() -> Result<PathBuf> {
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").context("XDG_RUNTIME_DIR is not set")?;
+    Ok(PathBuf::from(runtime_dir).join("paber.sock"))
+}
+
+/// A request a client sent, for the event loop to answer.
+pub struct IpcCall {
+    request: Request,
+    reply: mpsc::Sender<Response>,
+}
+
+impl IpcCall {
+    /// Sends the client what `handle` makes of its request.
+    pub fn answer(self, handle: impl FnOnce(Request) -> Response) {
+        // The client may have hung up in the meantime
+        let _ = self.reply.send(handle(self.request));
+    }
+}
+
+pub struct IpcServer {
+    listener: UnixListener,
+    path: PathBuf,
+    calls: Sender<IpcCall>,
+}
+
+impl IpcServer {
+    /// Listens on the socket. The requests of clients arrive on the returned channel.
+    pub fn bind() -> Result<(Self, Channel<IpcCall>)> {
+        let path = socket_path()?;
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                anyhow::bail!("Another paber daemon is already listening on {}", path.display());
+            }
+            // Left behind by a daemon that didn't shut down cleanly
+            fs::remove_file(&path).context("Failed to remove stale socket")?;
+        }
+
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        println!("Listening on {}", path.display());
+        let (calls, channel) = channel::channel();
+        Ok((IpcServer { listener, path, calls }, channel))
+    }
+
+    /// Accepts every client that is waiting, without blocking when there are none. Each
+    /// client is read from and written to on its own thread, so a slow one can't hold up
+    /// the event loop.
+    pub fn accept_pending(&self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    eprintln!("Failed to accept IPC connection: {e}");
+                    return;
+                }
+            };
+            let calls = self.calls.clone();
+            let ask = move |request| {
+                let (reply, answer) = mpsc::channel();
+                calls.send(IpcCall { request, reply }).map_err(|_| anyhow!("the daemon is shutting down"))?;
+                answer.recv().map_err(|_| anyhow!("the daemon is shutting down"))
+            };
+            thread::spawn(move || {
+                if let Err(e) = serve(stream, ask) {
+                    eprintln!("IPC error: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// Readable when a client is waiting, for the event loop
+impl AsFd for IpcServer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Reads one request, however many writes it arrives in, and writes the answer `ask` gets for it.
+fn serve(stream: UnixStream, ask: impl FnOnce(Request) -> Result<Response>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    // A client that connects and never writes only ties up its own thread, and not forever
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => ask(request)?,
+        Err(e) => Response::error(format!("Invalid request: {e}")),
+    };
+
+    let mut stream = stream;
+    serde_json::to_writer(&mut stream, &response)?;
+    stream.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Sends a request to the running daemon and waits for its answer.
+pub fn send(request: &Request) -> Result<Response> {
+    let path = socket_path()?;
+    let mut stream = UnixStream::connect(&path)
+        .with_context(|| format!("Failed to connect to {}. Is `paber daemon` running?", path.display()))?;
+
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    serde_json::from_str(&line).context("Invalid response from the daemon")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn output_status() -> OutputStatus {
+        OutputStatus {
+            index: 1,
+            name: Some("DP-1".to_string()),
+            description: None,
+            width: 2560,
+            height: 1440,
+            scale: 1.5,
+            source: Some("/wallpapers".to_string()),
+            current: Some("/wallpapers/a.png".to_string()),
+            paused: true,
+            error: None,
+            quarantined: Vec::new(),
+            generating: false,
+        }
+    }
+
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de>>(value: &T) -> (String, T) {
+        let json = serde_json::to_string(value).unwrap();
+        let parsed = serde_json::from_str(&json).unwrap();
+        (json, parsed)
+    }
+
+    #[test]
+    fn requests_survive_a_round_trip() {
+        let requests = [
+            Request::Set { monitors: Some("DP-1,0".to_string()), source: "/wallpapers".to_string() },
+            Request::Next { monitors: None },
+            Request::Prev { monitors: Some("*".to_string()) },
+            Request::Pause { monitors: None },
+            Request::Resume { monitors: None },
+            Request::Status,
+            Request::Reload,
+        ];
+        for request in requests {
+            assert_eq!(round_trip(&request).1, request);
+        }
+        assert_eq!(round_trip(&Request::Status).0, r#"{"command":"status"}"#);
+        assert_eq!(
+            serde_json::from_str::<Request>(r##"{"command":"set","source":"#000","monitors":null}"##).unwrap(),
+            Request::Set { monitors: None, source: "#000".to_string() },
+        );
+    }
+
+    #[test]
+    fn responses_survive_a_round_trip() {
+        let response = Response {
+            outputs: vec![output_status(), OutputStatus { error: Some("no such file".to_string()), quarantined: vec!["a.png".to_string()], ..output_status() }],
+            generation: Some(GenerationStatus { running: Some("generated".to_string()), queued: 2, ready: 1, cancelled: 3 }),
+            ..Response::ok()
+        };
+        assert_eq!(round_trip(&response).1, response);
+
+        // Empty fields are left out
+        let (json, parsed) = round_trip(&Response::error("unknown monitor"));
+        assert_eq!(json, r#"{"ok":false,"error":"unknown monitor"}"#);
+        assert_eq!(parsed, Response::error("unknown monitor"));
+        assert_eq!(round_trip(&Response::ok()).0, r#"{"ok":true}"#);
+    }
+
+    /// Sends `parts` from a client with a pause between them and returns the answer,
+    /// which echoes the request.
+    fn exchange(parts: &[&str]) -> Response {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || serve(server, |request| Ok(Response::error(format!("{request:?}")))));
+        let mut writer = &client;
+        for part in parts {
+            writer.write_all(part.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut answer = String::new();
+        (&client).read_to_string(&mut answer).unwrap();
+        server.join().unwrap().unwrap();
+        serde_json::from_str(&answer).unwrap()
+    }
+
+    #[test]
+    fn reads_a_request_written_in_parts() {
+        let response = exchange(&[r#"{"command":"ne"#, r#"xt","monitors":"DP-1"}"#, "\n"]);
+        assert_eq!(response.error.as_deref(), Some(r#"Next { monitors: Some("DP-1") }"#));
+    }
+
+    #[test]
+    fn answers_an_invalid_request_with_an_error() {
+        let response = exchange(&["{\"command\":\"explode\"}\n"]);
+        assert!(!response.ok);
+        assert!(response.error.unwrap().starts_with("Invalid request: unknown variant `explode`"));
+    }
+
+    #[test]
+    fn lists_an_output_on_one_line() {
+        assert_eq!(output_status().to_string(), "1: DP-1 2560x1440@1.5 /wallpapers -> /wallpapers/a.png (paused)");
+        let status = OutputStatus { name: None, source: None, current: None, paused: false, generating: true, ..output_status() };
+        assert_eq!(status.to_string(), "1: unknown 2560x1440@1.5 (no source) (generating)");
+    }
+
+    #[test]
+    fn lists_errors_and_quarantined_images_below_the_output() {
+        let status = OutputStatus {
+            error: Some("failed to load /wallpapers/b.png".to_string()),
+            quarantined: vec!["/wallpapers/b.png".to_string(), "/wallpapers/c.png".to_string()],
+            ..output_status()
+        };
+        assert_eq!(status.to_string(), "\
+1: DP-1 2560x1440@1.5 /wallpapers -> /wallpapers/a.png (paused)
+   error: failed to load /wallpapers/b.png
+   quarantined: /wallpapers/b.png
+   quarantined: /wallpapers/c.png");
+    }
+}
@@ -1,37 +1,97 @@
 use std::fs;
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
 use std::path::Path;
 
 use anyhow::{Context, Result};
+use nix::sys::signal::SigSet;
 
-pub fn generate_local(prompt: &str, output_path: &str) -> Result<()> {
-    println!("Running local image generation...");
-
-    let status = Command::new("nice")
-        .arg("-n").arg("19") // lowest priority
-        .arg("stable-diffusion")
-        .arg("--prompt")
-        .arg(prompt)
-        .arg("--sd-version").arg("v1-5")
-        .arg("--n-steps").arg("100")
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()
-        .context("Failed to execute 'stable-diffusion' command. Is it in your PATH?")?;
-
-    if !status.success() {
-        anyhow::bail!("Local generation failed. Check the logs above.");
+use crate::backend::{GeneratedImage, GenerationRequest, ImageGenerator, ImageMetadata};
+
+/// Runs stable-diffusion on this machine.
+pub struct LocalGenerator;
+
+impl ImageGenerator for LocalGenerator {
+    fn generate(&self, request: &GenerationRequest) -> Result<GeneratedImage> {
+        println!("Running local image generation...");
+
+        let mut command = Command::new("nice");
+        command
+            .arg("-n").arg("19") // lowest priority
+            .arg("stable-diffusion")
+            .arg("--prompt")
+            .arg(&request.prompt)
+            .arg("--sd-version").arg("v1-5")
+            .arg("--n-steps").arg("100");
+        if let Some((width, height)) = request.size.and_then(model_size) {
+            command.arg("--width").arg(width.to_string()).arg("--height").arg(height.to_string());
+        }
+        if let Some(seed) = request.seed {
+            command.arg("--seed").arg(seed.to_string());
+        }
+        if let Some(negative) = &request.negative_prompt {
+            command.arg("--uncond-prompt").arg(negative);
+        }
+        // The event loop blocks SIGINT, SIGTERM and SIGHUP and children inherit the mask,
+        // so a Ctrl+C or kill would otherwise leave stable-diffusion running.
+        // SAFETY: pthread_sigmask is async-signal-safe
+        unsafe {
+            command.pre_exec(|| SigSet::all().thread_unblock().map_err(|e| e.into()));
+        }
+        let status = command
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()
+            .context("Failed to execute 'stable-diffusion' command. Is it in your PATH?")?;
+
+        if !status.success() {
+            anyhow::bail!("Local generation failed. Check the logs above.");
+        }
+
+        let default_output = Path::new("sd_final.png");
+        
+        if !default_output.exists() {
+            anyhow::bail!("Success reported, but 'sd_final.png' was not found!");
+        }
+        let bytes = fs::read(default_output)
+            .context("Failed to read the generated image")?;
+        fs::remove_file(default_output)
+            .context("Failed to remove 'sd_final.png'")?;
+
+        let metadata = ImageMetadata { model: Some("v1-5".to_string()), seed: request.seed, ..Default::default() };
+        Ok(GeneratedImage { bytes, extension: "png", metadata })
     }
+}
 
-    let default_output = Path::new("sd_final.png");
-    
-    if default_output.exists() {
-        fs::rename(default_output, output_path)
-            .context("Failed to move the generated image to the output path")?;
-        println!("Local wallpaper saved to: {}", output_path);
-    } else {
-        anyhow::bail!("Success reported, but 'sd_final.png' was not found!");
+/// Keeps the aspect ratio of `size` at the resolution v1-5 was trained on: the shorter side
+/// at 512 pixels, both sides multiples of 8.
+fn model_size((width, height): (u32, u32)) -> Option<(u32, u32)> {
+    if width == 0 || height == 0 {
+        return None;
     }
+    let scale = 512.0 / width.min(height) as f64;
+    let side = |pixels: u32| ((pixels as f64 * scale / 8.0).round() as u32).max(1) * 8;
+    Some((side(width), side(height)))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_shape_at_the_model_resolution() {
+        let cases = [
+            ((1920, 1080), Some((912, 512))),
+            ((3840, 2160), Some((912, 512))),
+            ((2560, 1080), Some((1216, 512))),
+            ((3440, 1440), Some((1224, 512))),
+            ((1080, 1920), Some((512, 912))),
+            ((1024, 1024), Some((512, 512))),
+            ((256, 256), Some((512, 512))),
+            ((0, 1080), None),
+        ];
+        for (size, expected) in cases {
+            assert_eq!(model_size(size), expected, "{size:?}");
+        }
+    }
 }
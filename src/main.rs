@@ -1,25 +1,52 @@
 mod state;
+mod animation;
+mod backend;
+mod buffer;
 mod client;
+mod color;
+mod config;
+mod error;
 mod gai;
+mod generate;
+mod ipc;
 mod lai;
+mod monitor;
+mod playlist;
+mod render;
+mod schedule;
+mod source;
+mod timer;
+mod transition;
+mod watch;
 
 use anyhow::Result;
-use chrono::{Local, Timelike};
-use clap::Parser;
-use uuid::Uuid;
+use chrono::{DateTime, Local, Timelike};
+use clap::{Parser, Subcommand};
+use calloop::{EventLoop, Interest, Mode, PostAction, channel::{self, Channel}, generic::Generic, signals::{Signal, Signals}};
+use calloop_wayland_source::WaylandSource;
+use glob::Pattern;
+use image::ImageFormat;
 
-use std::{env, fs, path::{PathBuf}, time::{Duration, Instant}, usize};
+use std::{env, fmt::Display, fs, iter, path::{Path, PathBuf}, process, rc::Rc, str::FromStr, time::{Duration, Instant}};
 
 use wayland_client::{Connection, EventQueue, QueueHandle};
 
-use crate::{client::{build_state, build_surface, draw_plain, set_img}, gai::WallpaperTool, lai::generate_local, state::AppState};
+use crate::{backend::GenerationRequest, client::{build_state, build_surface, create_missing_surfaces, draw_animation_frame, draw_plain, set_imgs}, color::{Color, Fill}, config::{Config, OutputConfig, default_config_path, format_errors, load_config, report_errors}, error::PaberError, generate::{GenerationEvent, GenerationKey, Generator, generate_img}, ipc::{IpcCall, IpcServer, OutputStatus, Request, Response}, monitor::{check_monitors, matches_monitor, monitor_number, select_monitors, spec_matches}, playlist::{Order, Playlist, PlaylistStore, parse_glob}, render::{ScaleMode, render_image}, schedule::Schedule, source::Source, state::{AppState, Wallpaper}, timer::DeadlineTimer, transition::{Easing, TransitionKind, parse_seconds}, watch::DirWatcher};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Sets a plain wallpaper with a specified hex value
-    #[arg(long)]
-    plain: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file to use instead of $XDG_CONFIG_HOME/paber/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Sets a plain wallpaper with a colour (hex, rgb(), hsl() or a CSS name)
+    /// or a linear-gradient(), radial-gradient() or conic-gradient()
+    #[arg(long, value_parser = Fill::parse)]
+    plain: Option<Fill>,
 
     /// Sets the specified image as a wallpaper
     #[arg(long)]
@@ -33,37 +60,214 @@ struct Args {
     #[arg(long)]
     prompt: Option<String>,
 
-    /// Cycles through images in a specified directory
+    /// Cycles through images in a directory. Can be repeated to cycle through several
+    #[arg(long)]
+    cycle: Vec<String>,
+
+    /// Also cycles through images in subdirectories
+    #[arg(long)]
+    recursive: bool,
+
+    /// Levels of subdirectories to scan, implies --recursive
     #[arg(long)]
-    cycle: Option<String>,
+    max_depth: Option<usize>,
+
+    /// Only cycles through files matching GLOB instead of every image, e.g. "*.png"
+    /// or "2024/**". Matched against the path inside the directory. Can be repeated
+    #[arg(long = "include", value_name = "GLOB", value_parser = parse_glob)]
+    includes: Vec<Pattern>,
 
-    /// Monitors to apply the wallpaper to
+    /// Skips files and directories matching GLOB. Can be repeated
+    #[arg(long = "exclude", value_name = "GLOB", value_parser = parse_glob)]
+    excludes: Vec<Pattern>,
+
+    /// Descends into symlinked directories while scanning
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Includes files and directories whose name starts with a dot
+    #[arg(long)]
+    hidden: bool,
+
+    /// Monitors to apply the wallpaper to: indexes, connector names (DP-1),
+    /// globs on the name or description ("Dell*") or "all"
     #[arg(short, long)]
     monitors: Option<String>,
 
-    /// Interval for the cycle mode in seconds
-    #[arg(short, long)]
-    interval: Option<u64>,
+    /// How often cycle mode switches images and generated wallpapers are generated again:
+    /// seconds, a duration like 3h or 1h30m, or "every day at 07:00" / "every monday at 07:00".
+    /// Use MONITOR=INTERVAL to set it for a single monitor
+    #[arg(short, long = "interval", value_name = "INTERVAL", value_parser = per_monitor::<Schedule>)]
+    intervals: Vec<(Option<String>, Schedule)>,
+
+    /// Order of images in cycle mode: name, natural, mtime, random or shuffle.
+    /// Use MONITOR=ORDER to set it for a single monitor
+    #[arg(long = "order", value_name = "ORDER", value_parser = per_monitor::<Order>)]
+    orders: Vec<(Option<String>, Order)>,
 
     /// Sets the generated image mode to be local
     #[arg(long)]
     local: bool,
+
+    /// How images are scaled: fill, fit, center, tile or stretch.
+    /// Use MONITOR=MODE to set it for a single monitor, e.g. --mode 1=fit
+    #[arg(long = "mode", value_name = "MODE", value_parser = per_monitor::<ScaleMode>)]
+    modes: Vec<(Option<String>, ScaleMode)>,
+
+    /// Colour or gradient shown around images in fit, center and tile modes
+    /// and behind transparent images. Use MONITOR=COLOUR to set it for a single monitor
+    #[arg(long = "background", value_name = "BACKGROUND", value_parser = per_monitor::<Fill>)]
+    backgrounds: Vec<(Option<String>, Fill)>,
+
+    /// Assigns a source to a monitor: MONITOR=IMAGE, MONITOR=DIRECTORY (cycled),
+    /// MONITOR=COLOUR or MONITOR=generated[:PROMPT]. Can be repeated
+    #[arg(long = "output", value_name = "MONITOR=SOURCE", value_parser = parse_output_arg)]
+    outputs: Vec<(String, Source)>,
+
+    /// How a new wallpaper replaces the previous one: none, crossfade, wipe [ANGLE],
+    /// slide [ANGLE] or grow [at POSITION], e.g. "wipe to left" or "grow at 20% 80%"
+    #[arg(long, value_parser = TransitionKind::from_str)]
+    transition: Option<TransitionKind>,
+
+    /// Length of a transition in seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    transition_duration: Option<Duration>,
+
+    /// Transition easing: linear, ease-in, ease-out or ease-in-out
+    #[arg(long, value_name = "EASING", value_parser = Easing::from_str)]
+    transition_easing: Option<Easing>,
+
+    /// How often animated GIF, APNG and WebP images play, 0 for endless
+    #[arg(long)]
+    loops: Option<u32>,
+
+    /// Upper limit for the frame rate of animated images
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_fps: Option<u32>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validates the configuration file and reports errors with their line numbers
+    CheckConfig,
+    /// Keeps running and accepts commands from `paber ctl` on $XDG_RUNTIME_DIR/paber.sock
+    Daemon,
+    /// Controls a running daemon
+    Ctl {
+        #[command(subcommand)]
+        action: CtlCommand,
+    },
+    /// Draws a wallpaper to a PNG file without a compositor
+    Render {
+        /// Size in pixels, e.g. 3840x2160
+        #[arg(long, value_parser = parse_size)]
+        size: (u32, u32),
+        /// What to draw: an image, a directory (its first image in cycle order),
+        /// a colour or gradient, or generated[:PROMPT]
+        #[arg(long, value_parser = Source::parse)]
+        source: Source,
+        /// PNG file to write
+        #[arg(short, long)]
+        output: PathBuf,
+        /// How the image is scaled: fill, fit, center, tile or stretch
+        #[arg(long = "mode", value_name = "MODE", value_parser = ScaleMode::from_str)]
+        mode: Option<ScaleMode>,
+        /// Colour or gradient around and behind the image
+        #[arg(long = "background", value_name = "BACKGROUND", value_parser = Fill::parse)]
+        background: Option<Fill>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Shows SOURCE (an image, directory, colour or generated[:PROMPT]) on the selected monitors
+    Set {
+        source: String,
+        /// Monitors to change, defaults to all
+        #[arg(short, long)]
+        monitors: Option<String>,
+    },
+    /// Switches cycling monitors to their next image and generates a new one for generated wallpapers
+    Next {
+        #[arg(short, long)]
+        monitors: Option<String>,
+    },
+    /// Switches cycling monitors to their previous image
+    Prev {
+        #[arg(short, long)]
+        monitors: Option<String>,
+    },
+    /// Stops cycling on the selected monitors
+    Pause {
+        #[arg(short, long)]
+        monitors: Option<String>,
+    },
+    /// Continues cycling on the selected monitors
+    Resume {
+        #[arg(short, long)]
+        monitors: Option<String>,
+    },
+    /// Lists the monitors and what they show
+    Status,
+    /// Re-reads the configuration file
+    Reload,
 }
 
 fn main() {
     let args = Args::parse();
-    let mode = determine_mode(&args).expect("Expected mode");
-    let conn = Connection::connect_to_env().expect("Failed to connect to Wayland");
+    match &args.command {
+        Some(Command::CheckConfig) => process::exit(check_config(&args)),
+        Some(Command::Ctl { action }) => process::exit(ctl(action)),
+        Some(Command::Render { .. }) => {
+            if let Err(e) = render(&args) {
+                eprintln!("Error: {e}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Daemon) | None => {}
+    }
+
+    let ipc = matches!(args.command, Some(Command::Daemon)).then(|| IpcServer::bind().unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    })).map(|(server, calls)| (Rc::new(server), calls));
+
+    let mut config = read_config(&args);
+    apply_args(&args, &mut config);
+    let assignments = determine_assignments(&args, &config);
+    if assignments.is_empty() && ipc.is_none() {
+        eprintln!("Error: no mode found");
+        process::exit(1);
+    }
+    if let Err(e) = run(args, config, &assignments, ipc) {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    }
+}
+
+/// Connects to the compositor, shows the wallpapers and keeps running until paber is stopped.
+fn run(args: Args, config: Config, assignments: &[(String, Source)], ipc: Option<(Rc<IpcServer>, Channel<IpcCall>)>) -> Result<(), PaberError> {
+    let conn = Connection::connect_to_env()?;
 
     let mut event_queue = conn.new_event_queue();
     let qh = event_queue.handle();
-    let mut state = build_state(&conn, &mut event_queue);
-    build_surface(&mut state, &qh);
+    let mut state = build_state(&conn, &mut event_queue)?;
+    state.transition = config.transition;
+    state.animation = config.animation;
+    state.scan = config.scan.clone();
+    state.home = paber_home(&config);
+    // Blocks the signals on this thread before any other thread is spawned, so the generator
+    // worker inherits the mask and the signals reach the event loop instead of killing paber
+    let signals = Signals::new(&[Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP]).map_err(event_loop_error)?;
+    let (sender, generated) = channel::channel();
+    state.generator = Some(Generator::new(sender));
+    build_surface(&mut state, &qh)?;
 
     println!("Surface created! Waiting for configuration...");
 
     loop {
-        event_queue.blocking_dispatch(&mut state).unwrap();
+        event_queue.blocking_dispatch(&mut state)?;
 
         let all_ready = state.wallpapers.iter().all(|w| w.configured);
         if all_ready {
@@ -71,42 +275,490 @@ fn main() {
     }
 
     println!("Configuration complete. Ready to draw background");
-    let monitors_to_apply = parse_monitors(&args);
-
-    match &mode {
-        Mode::PLAIN => monitors_to_apply.iter().for_each(|m_index| draw_plain(&state, &qh, *m_index)),
-        Mode::IMAGE(image) => monitors_to_apply.iter().for_each(|m_index| set_img(&state, &qh, &image, *m_index)),
-        Mode::GENERATED(prompt) => set_generated_img(prompt, args.local, &state, &qh, monitors_to_apply).unwrap(),
-        Mode::CYCLE(path, interval) => cycle_images(&path, interval, &mut state, &qh, &mut event_queue, &conn, monitors_to_apply),
+    for (monitors, _) in assignments {
+        check_monitors(monitors, &state).map_err(PaberError::Monitor)?;
     }
+    update_outputs(&mut state, &qh, &args, &config);
 
     println!("Wallpaper set! Press Ctrl+C to exit");
 
-    loop {
-        event_queue.blocking_dispatch(&mut state).unwrap();
+    let timer = Rc::new(DeadlineTimer::new().map_err(event_loop_error)?);
+    let watcher = match DirWatcher::new() {
+        Ok(watcher) => Some(Rc::new(watcher)),
+        Err(e) => {
+            eprintln!("Failed to set up inotify, changes to cycled directories are not picked up: {e}");
+            None
+        }
+    };
+    let (ipc, calls) = ipc.unzip();
+    let app = App { state, qh, args, config, ipc, timer, watcher };
+    run_event_loop(app, conn, event_queue, generated, calls, signals)
+}
+
+/// Everything the event loop callbacks work on.
+struct App {
+    state: AppState,
+    qh: QueueHandle<AppState>,
+    args: Args,
+    config: Config,
+    ipc: Option<Rc<IpcServer>>,
+    /// Fires at the next cycle switch or animation frame
+    timer: Rc<DeadlineTimer>,
+    /// Notices images being added to or removed from cycled directories
+    watcher: Option<Rc<DirWatcher>>,
+}
+
+/// Sleeps until the Wayland connection, the IPC socket, a generated image, a signal or
+/// the deadline timer wakes it up. Returns on SIGINT or SIGTERM, SIGHUP reloads the config.
+fn run_event_loop(mut app: App, conn: Connection, event_queue: EventQueue<AppState>, generated: Channel<GenerationEvent>, calls: Option<Channel<IpcCall>>, signals: Signals) -> Result<(), PaberError> {
+    let mut event_loop: EventLoop<App> = EventLoop::try_new().map_err(event_loop_error)?;
+    let handle = event_loop.handle();
+
+    handle.insert_source(WaylandSource::new(conn, event_queue), |_, queue, app| {
+        queue.dispatch_pending(&mut app.state)
+    }).map_err(event_loop_error)?;
+
+    // The work itself is done after every dispatch, the timer only wakes the loop
+    handle.insert_source(Generic::new(app.timer.clone(), Interest::READ, Mode::Level), |_, timer, _| {
+        timer.clear();
+        Ok(PostAction::Continue)
+    }).map_err(event_loop_error)?;
+
+    handle.insert_source(generated, |event, _, app| {
+        if let channel::Event::Msg(event) = event {
+            show_generated(&mut app.state, &app.qh, event, &app.config);
+        }
+    }).map_err(event_loop_error)?;
+
+    if let Some(ipc) = &app.ipc {
+        handle.insert_source(Generic::new(ipc.clone(), Interest::READ, Mode::Level), |_, ipc, _| {
+            ipc.accept_pending();
+            Ok(PostAction::Continue)
+        }).map_err(event_loop_error)?;
+    }
+    if let Some(calls) = calls {
+        handle.insert_source(calls, |event, _, app| {
+            if let channel::Event::Msg(call) = event {
+                call.answer(|request| handle_request(request, &app.args, &mut app.config, &mut app.state, &app.qh));
+            }
+        }).map_err(event_loop_error)?;
+    }
+
+    if let Some(watcher) = &app.watcher {
+        handle.insert_source(Generic::new(watcher.clone(), Interest::READ, Mode::Level), |_, watcher, app| {
+            schedule_rescans(&mut app.state, &watcher.changed_dirs());
+            Ok(PostAction::Continue)
+        }).map_err(event_loop_error)?;
+    }
+
+    let stop = event_loop.get_signal();
+    handle.insert_source(signals, move |event, _, app| match event.signal() {
+        Signal::SIGHUP => {
+            if let Err(e) = reload(&app.args, &mut app.config, &mut app.state, &app.qh) {
+                eprintln!("Failed to reload the configuration:\n{e}");
+            }
+        }
+        _ => {
+            println!("Exiting");
+            stop.stop();
+        }
+    }).map_err(event_loop_error)?;
+
+    app.timer.set(next_deadline(&app.state));
+    event_loop.run(None, &mut app, |app| {
+        run_due(&mut app.state, &app.qh, &app.config);
+        update_outputs(&mut app.state, &app.qh, &app.args, &app.config);
+        update_generations(&mut app.state, &app.qh, &app.config);
+        if let Some(watcher) = &app.watcher {
+            watcher.watch(app.state.wallpapers.iter().filter_map(|w| w.playlist.as_ref()).flat_map(|p| p.dirs()));
+        }
+        app.timer.set(next_deadline(&app.state));
+    }).map_err(event_loop_error)
+}
+
+fn event_loop_error(e: impl Display) -> PaberError {
+    PaberError::EventLoop(e.to_string())
+}
+
+/// Syncs often write many files in a row, so playlists are scanned once things settled down
+const RESCAN_DELAY: Duration = Duration::from_secs(1);
+
+/// Schedules a rescan of every playlist that gets images from one of the `changed` directories.
+fn schedule_rescans(state: &mut AppState, changed: &[PathBuf]) {
+    let at = Instant::now() + RESCAN_DELAY;
+    for playlist in state.wallpapers.iter_mut().filter_map(|w| w.playlist.as_mut()) {
+        if changed.iter().any(|dir| playlist.contains_dir(dir)) {
+            playlist.rescan_at.get_or_insert(at);
+        }
     }
 }
 
-fn set_generated_img(prompt: &str, is_local: bool, state: &AppState, qh: &QueueHandle<AppState>, monitors: Vec<usize>) -> Result<()> {
-    let output_suffix = Uuid::new_v4();
-    let output = env::var("PABER_HOME").expect("PABER_HOME is not set") + "generated/generated" + &output_suffix.to_string() + ".png";
-    if is_local {
-       generate_local(&prompt, &output)?;
-    } else {
-        let wt = WallpaperTool::new()?;
-        wt.generate_online(&prompt, &output)?;
+/// Rescans changed playlists, switches cycles, starts generating and draws animation
+/// frames whose time has come.
+fn run_due(state: &mut AppState, qh: &QueueHandle<AppState>, config: &Config) {
+    let now = Instant::now();
+    for wallpaper in &mut state.wallpapers {
+        wallpaper.sync_switch();
+        if let Some(playlist) = &mut wallpaper.playlist
+            && playlist.rescan_at.is_some_and(|t| now >= t)
+        {
+            playlist.rescan(&state.scan);
+            // Nothing to continue from, e.g. the directory was empty, so an image is shown right away
+            if playlist.current_image().is_none() {
+                wallpaper.switch_now();
+            }
+        }
     }
-    monitors.iter().for_each(|m_index| set_img(state, qh, &output, *m_index));
-    Ok(())
+    let due: Vec<usize> = (0..state.wallpapers.len())
+        .filter(|&index| {
+            let wallpaper = &state.wallpapers[index];
+            !wallpaper.paused && wallpaper.next_switch.is_some_and(|t| now >= t)
+        })
+        .collect();
+    if !due.is_empty() {
+        show_next(state, qh, &due, true);
+        start_generation(state, qh, &due, config);
+    }
+    for index in 0..state.wallpapers.len() {
+        if state.wallpapers[index].animation.as_ref().is_some_and(|a| a.is_due(now)) {
+            draw_animation_frame(state, qh, index);
+        }
+    }
+}
+
+/// The earliest cycle switch, animation frame or rescan, if anything is scheduled.
+fn next_deadline(state: &AppState) -> Option<Instant> {
+    let switches = state.wallpapers.iter()
+        .filter(|w| !w.paused)
+        .filter_map(|w| w.next_switch);
+    let frames = state.wallpapers.iter()
+        .filter_map(|w| w.animation.as_ref().filter(|a| !a.waiting)?.next_frame);
+    let rescans = state.wallpapers.iter()
+        .filter_map(|w| w.playlist.as_ref()?.rescan_at);
+    switches.chain(frames).chain(rescans).min()
 }
 
-fn build_enriched_prompt(user_prompt: &Option<String>) -> String {
-    let user = env::var("USER").unwrap();
+/// Creates surfaces for new outputs, gives newly configured ones their source
+/// and redraws the ones that changed size.
+fn update_outputs(state: &mut AppState, qh: &QueueHandle<AppState>, args: &Args, config: &Config) {
+    create_missing_surfaces(state, qh);
+
+    let pending: Vec<usize> = (0..state.wallpapers.len())
+        .filter(|&index| state.wallpapers[index].configured && state.wallpapers[index].needs_setup)
+        .collect();
+    if !pending.is_empty() {
+        let assignments = determine_assignments(args, config);
+        for &index in &pending {
+            for (monitors, source) in &assignments {
+                if spec_matches(monitors, index, state) {
+                    state.wallpapers[index].source = Some(source.clone());
+                }
+            }
+            // A source set at runtime outlives the surface, e.g. when the compositor closed it
+            if let Some(source) = state.runtime_sources.get(&monitor_number(state, index)) {
+                state.wallpapers[index].source = Some(source.clone());
+            }
+        }
+        apply_per_monitor(args, config, state, &pending);
+        for &index in &pending {
+            let wallpaper = &mut state.wallpapers[index];
+            wallpaper.needs_setup = false;
+            wallpaper.needs_redraw = false;
+        }
+        apply_sources(state, qh, &pending);
+    }
+
+    let redraw_needed: Vec<usize> = (0..state.wallpapers.len())
+        .filter(|&index| state.wallpapers[index].needs_redraw)
+        .collect();
+    if !redraw_needed.is_empty() {
+        for &index in &redraw_needed {
+            state.wallpapers[index].needs_redraw = false;
+        }
+        redraw(state, qh, &redraw_needed);
+    }
+}
+
+/// Draws what wallpapers currently show again, e.g. after their output changed size.
+fn redraw(state: &mut AppState, qh: &QueueHandle<AppState>, indexes: &[usize]) {
+    let mut images = Vec::new();
+    for &index in indexes {
+        let wallpaper = &state.wallpapers[index];
+        if let Some(playlist) = &wallpaper.playlist {
+            if let Some(img_path) = playlist.current_image() {
+                images.push((index, img_path.to_string_lossy().into_owned()));
+            }
+            continue;
+        }
+        match wallpaper.source.clone() {
+            Some(Source::Plain(fill)) => draw_plain(state, qh, &fill, index),
+            Some(Source::Image(image)) => images.push((index, image)),
+            Some(Source::Generated(_)) => images.extend(wallpaper.generated.clone().map(|image| (index, image))),
+            _ => {}
+        }
+    }
+    show_images(state, qh, images);
+}
+
+/// Shows images on wallpapers. A cycle image that fails to load is quarantined and the
+/// next one is tried, any other failure leaves the previous wallpaper on screen.
+/// Returns the wallpapers that couldn't be updated and why.
+fn show_images(state: &mut AppState, qh: &QueueHandle<AppState>, mut images: Vec<(usize, String)>) -> Vec<(usize, PaberError)> {
+    let mut failures = Vec::new();
+    while !images.is_empty() {
+        let mut retries = Vec::new();
+        for (index, error) in set_imgs(state, qh, images) {
+            let wallpaper = &mut state.wallpapers[index];
+            wallpaper.report(&error);
+            if let PaberError::Image { path, .. } = &error
+                && let Some(playlist) = &mut wallpaper.playlist
+                && playlist.current_image() == Some(path)
+            {
+                playlist.quarantine(path);
+                // Quarantined images are skipped, so this ends once every image was tried
+                if let Some(next) = playlist.advance() {
+                    let next = next.to_string_lossy().into_owned();
+                    println!("Switching to {next}");
+                    retries.push((index, next));
+                    continue;
+                }
+            }
+            failures.push((index, error));
+        }
+        images = retries;
+    }
+    failures
+}
+
+/// Shows the current source of wallpapers. Generated sources start generating right
+/// away and show the image once it is ready. Returns the wallpapers that couldn't be
+/// updated and why.
+fn apply_sources(state: &mut AppState, qh: &QueueHandle<AppState>, indexes: &[usize]) -> Vec<(usize, PaberError)> {
+    let mut images = Vec::new();
+    let mut failures = Vec::new();
+    for &index in indexes {
+        // An image that is still being generated is for the previous source
+        state.wallpapers[index].generating = false;
+        match state.wallpapers[index].source.clone() {
+            Some(Source::Plain(fill)) => draw_plain(state, qh, &fill, index),
+            Some(Source::Image(image)) => images.push((index, image)),
+            Some(Source::Cycle(dirs)) => {
+                let store = state.home.as_deref().map(|home| PlaylistStore::new(home, monitor_name(state, index), dirs.clone()));
+                let wallpaper = &mut state.wallpapers[index];
+                let mut playlist = Playlist::from_dirs(&dirs, &state.scan, wallpaper.order, store);
+                if playlist.is_empty() {
+                    let error = PaberError::NoImages(dirs.join(", "));
+                    wallpaper.report(&error);
+                    failures.push((index, error));
+                }
+                // A playlist saved by the previous run shows where it left off
+                let current = match playlist.current_image() {
+                    Some(current) => Some(current),
+                    None => playlist.advance(),
+                };
+                if let Some(current) = current {
+                    images.push((index, current.to_string_lossy().into_owned()));
+                }
+                wallpaper.playlist = Some(playlist);
+                wallpaper.schedule_switch();
+            }
+            Some(Source::Generated(_)) => state.wallpapers[index].switch_now(),
+            None => {}
+        }
+    }
+    failures.extend(show_images(state, qh, images));
+    failures
+}
+
+/// Switches the generated wallpapers among `indexes` to a new image. A prefetched image
+/// is shown right away, otherwise one is queued and `show_generated` shows it when it is ready.
+fn start_generation(state: &mut AppState, qh: &QueueHandle<AppState>, indexes: &[usize], config: &Config) {
+    let mut images = Vec::new();
+    // Wallpapers switching together with the same prompt and aspect ratio share one image
+    let mut taken: Vec<(GenerationKey, String)> = Vec::new();
+    for &index in indexes {
+        let Some(key) = state.wallpapers[index].generation_key() else {
+            continue;
+        };
+        let size = generation_size(state, &key);
+        let Some(generator) = &mut state.generator else {
+            return;
+        };
+        let wallpaper = &mut state.wallpapers[index];
+        // Scheduled again once the image is shown
+        wallpaper.next_switch = None;
+        if key.aspect.is_none() {
+            // Generated for by update_generations once the compositor gives the output a size
+            wallpaper.generating = true;
+            continue;
+        }
+
+        let ready = match taken.iter().find(|(taken_key, _)| *taken_key == key) {
+            Some((_, path)) => Some(path.clone()),
+            None => generator.take_ready(&key).inspect(|path| taken.push((key.clone(), path.clone()))),
+        };
+        match ready {
+            Some(path) => {
+                wallpaper.generating = false;
+                wallpaper.generated = Some(path.clone());
+                wallpaper.schedule_switch();
+                images.push((index, path));
+            }
+            None => {
+                wallpaper.generating = true;
+                // A job that is already queued, e.g. a prefetch, serves every wallpaper with the key
+                if generator.queued_for(&key) == 0 {
+                    queue_generation(generator, &key, size, Local::now(), config, state.home.clone());
+                }
+            }
+        }
+    }
+    show_images(state, qh, images);
+    for (key, _) in taken {
+        prefetch(state, &key, config);
+    }
+}
 
+/// Queues an image with a prompt enriched for `at`, the time it will be shown.
+fn queue_generation(generator: &mut Generator, key: &GenerationKey, size: Option<(u32, u32)>, at: DateTime<Local>, config: &Config, home: Option<PathBuf>) {
+    match size {
+        Some((width, height)) => println!("Queued generating {} at {width}x{height}", Source::Generated(key.user_prompt.clone())),
+        None => println!("Queued generating {}", Source::Generated(key.user_prompt.clone())),
+    }
+    let request = GenerationRequest { size, ..generation_request(&key.user_prompt, at, config) };
+    generator.queue(request, key.clone(), home, config.generator.clone());
+}
+
+/// The largest buffer among the wallpapers `key` is for, the size its images are generated at.
+fn generation_size(state: &AppState, key: &GenerationKey) -> Option<(u32, u32)> {
+    state.wallpapers.iter()
+        .filter(|w| w.generation_key().as_ref() == Some(key))
+        .map(|w| w.buffer_size())
+        .filter(|&(width, height)| width > 0 && height > 0)
+        .max_by_key(|&(width, height)| width as u64 * height as u64)
+}
+
+/// Queues images for `key` ahead of time, so the next switch of its wallpapers
+/// doesn't wait for the generator. Only wallpapers generated on a schedule prefetch.
+fn prefetch(state: &mut AppState, key: &GenerationKey, config: &Config) {
+    let matching = |w: &&Wallpaper| w.generation_key().as_ref() == Some(key);
+    let Some(schedule) = state.wallpapers.iter().filter(matching).find_map(|w| w.interval) else {
+        return;
+    };
+    let waiting = state.wallpapers.iter().filter(matching).any(|w| w.generating);
+    let size = generation_size(state, key);
+
+    // Each image is enriched for the switch it will be shown at, not for now
     let now = Local::now();
-    let date_str = now.format("%A, %B %d, %Y").to_string();
+    let next_switch = state.wallpapers.iter().filter(matching)
+        .filter_map(|w| w.switch_at.or_else(|| Some(now + w.next_switch?.saturating_duration_since(Instant::now()))))
+        .min()
+        .unwrap_or_else(|| schedule.next_after(now));
+    let switches = iter::successors(Some(next_switch), |&at| Some(schedule.next_after(at)));
+
+    let Some(generator) = &mut state.generator else {
+        return;
+    };
+    let (ahead, missing) = generator.to_prefetch(key, config.generator.prefetch, waiting);
+    for at in switches.skip(ahead).take(missing) {
+        queue_generation(generator, key, size, at, config, state.home.clone());
+    }
+}
+
+/// Shows a finished image on the wallpapers waiting for one with its key and schedules
+/// their next switch. An image nobody waits for was prefetched and is kept for later.
+fn show_generated(state: &mut AppState, qh: &QueueHandle<AppState>, event: GenerationEvent, config: &Config) {
+    let Some((key, result)) = state.generator.as_mut().and_then(|generator| generator.handle(event)) else {
+        return;
+    };
+    let waiting: Vec<usize> = (0..state.wallpapers.len())
+        .filter(|&index| state.wallpapers[index].generating && state.wallpapers[index].generation_key() == Some(key.clone()))
+        .collect();
+    let path = match result {
+        Ok(path) => path,
+        Err(e) if waiting.is_empty() => {
+            eprintln!("Error: {e}");
+            return;
+        }
+        Err(e) => {
+            // The previous wallpaper stays until the next switch
+            for index in waiting {
+                let wallpaper = &mut state.wallpapers[index];
+                wallpaper.generating = false;
+                wallpaper.schedule_switch();
+                wallpaper.report(&e);
+            }
+            return;
+        }
+    };
+    if waiting.is_empty() {
+        if let Some(generator) = &mut state.generator {
+            generator.store_ready(key, path);
+        }
+        return;
+    }
+    for &index in &waiting {
+        let wallpaper = &mut state.wallpapers[index];
+        wallpaper.generating = false;
+        wallpaper.generated = Some(path.clone());
+        wallpaper.schedule_switch();
+    }
+    show_images(state, qh, waiting.into_iter().map(|index| (index, path.clone())).collect());
+    prefetch(state, &key, config);
+}
 
-    let hour = now.hour();
+/// Cancels generating for prompts and aspect ratios no wallpaper shows anymore. Wallpapers
+/// left waiting, because their output got a size or changed shape, are generated for again.
+fn update_generations(state: &mut AppState, qh: &QueueHandle<AppState>, config: &Config) {
+    let wanted: Vec<GenerationKey> = state.wallpapers.iter().filter_map(Wallpaper::generation_key).collect();
+    let Some(generator) = &mut state.generator else {
+        return;
+    };
+    generator.retain(|key| wanted.contains(key));
+    let stranded: Vec<usize> = (0..state.wallpapers.len())
+        .filter(|&index| state.wallpapers[index].generating)
+        .filter(|&index| {
+            state.wallpapers[index].generation_key()
+                .is_some_and(|key| key.aspect.is_some() && generator.queued_for(&key) == 0)
+        })
+        .collect();
+    if !stranded.is_empty() {
+        start_generation(state, qh, &stranded, config);
+    }
+}
+
+/// PABER_HOME, or the home from the config file.
+fn paber_home(config: &Config) -> Option<PathBuf> {
+    env::var("PABER_HOME").ok().or(config.home.clone()).map(PathBuf::from)
+}
+
+/// Generates an image for a generated source with `user_prompt`, or the configured prompt,
+/// and waits for it.
+fn generate_for(user_prompt: &Option<String>, size: (u32, u32), config: &Config) -> Result<String, PaberError> {
+    let request = GenerationRequest { size: Some(size), ..generation_request(user_prompt, Local::now(), config) };
+    generate_img(&request, paber_home(config).as_deref(), &config.generator)
+}
+
+/// What to ask the backends for a generated source with `user_prompt`, shown at `at`.
+fn generation_request(user_prompt: &Option<String>, at: DateTime<Local>, config: &Config) -> GenerationRequest {
+    let user_prompt = user_prompt.clone().or(config.prompt.text.clone());
+    GenerationRequest {
+        prompt: build_enriched_prompt(&user_prompt, config.prompt.enrich, at),
+        size: None,
+        seed: config.generator.seed,
+        negative_prompt: config.prompt.negative.clone(),
+    }
+}
+
+fn build_enriched_prompt(user_prompt: &Option<String>, enrich: bool, at: DateTime<Local>) -> String {
+    if !enrich {
+        return user_prompt.clone().unwrap_or("Generate a desktop wallpaper".to_string());
+    }
+    let user = env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+
+    let date_str = at.format("%A, %B %d, %Y").to_string();
+
+    let hour = at.hour();
     let time_of_day = match hour {
         5..=11 => "morning",
         12..=17 => "afternoon",
@@ -118,86 +770,401 @@ fn build_enriched_prompt(user_prompt: &Option<String>) -> String {
         "Generate a desktop wallpaper. Context: The user is {}, it is a {} on {}",
         user, time_of_day, date_str
     );
-    if user_prompt.is_some() {
-       return format!("{}. Request: {}", context, user_prompt.clone().unwrap());
+    if let Some(user_prompt) = user_prompt {
+       return format!("{}. Request: {}", context, user_prompt);
     }
     context
 }
 
-fn parse_monitors(args: &Args) -> Vec<usize> {
-    if args.monitors.is_some() {
-        return args.monitors.clone().unwrap().split(",").map(|x| x.parse().expect("Not a number!")).collect();
+/// Parses `VALUE` or `MONITOR=VALUE` for options that can be set per monitor.
+fn per_monitor<T: FromStr>(s: &str) -> Result<(Option<String>, T), String>
+where
+    T::Err: Display,
+{
+    match s.split_once('=') {
+        Some((monitor, value)) => Ok((Some(monitor.trim().to_string()), value.trim().parse().map_err(|e: T::Err| e.to_string())?)),
+        None => Ok((None, s.trim().parse().map_err(|e: T::Err| e.to_string())?)),
     }
-    vec![0]
 }
 
-enum Mode {
-    PLAIN,IMAGE(String),GENERATED(String),CYCLE(String, Duration)
+/// Parses `WIDTHxHEIGHT`.
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s.split_once(['x', 'X']).ok_or(format!("'{s}' is not a size like 1920x1080"))?;
+    let parse = |v: &str| v.trim().parse::<u32>().ok().filter(|&v| v > 0).ok_or(format!("'{v}' is not a positive number of pixels"));
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_output_arg(s: &str) -> Result<(String, Source), String> {
+    let (monitor, value) = s.split_once('=')
+        .ok_or_else(|| format!("expected MONITOR=SOURCE, got '{s}'"))?;
+    Ok((monitor.trim().to_string(), Source::parse(value)?))
 }
 
-fn determine_mode(args: &Args) -> Result<Mode, String> {
-    if args.plain.is_some() {
-        return Ok(Mode::PLAIN);
+/// Picks the value for a monitor: the last entry naming it, or else the last entry without a monitor.
+fn value_for<'a, T>(values: &'a [(Option<String>, T)], index: usize, state: &AppState) -> Option<&'a T> {
+    let specific = values.iter().rev()
+        .find(|(monitor, _)| monitor.as_deref().is_some_and(|m| matches_monitor(m, index, state)));
+    let global = values.iter().rev().find(|(monitor, _)| monitor.is_none());
+    specific.or(global).map(|(_, value)| value)
+}
+
+/// Merges a per-monitor setting from the config file and the command line.
+/// Config values come first so the command line wins.
+fn merged<T: Clone>(global: &Option<T>, outputs: &[OutputConfig], field: impl Fn(&OutputConfig) -> &Option<T>, cli: &[(Option<String>, T)]) -> Vec<(Option<String>, T)> {
+    let mut values: Vec<_> = global.iter().map(|value| (None, value.clone())).collect();
+    values.extend(outputs.iter().filter_map(|o| field(o).clone().map(|value| (Some(o.monitor.clone()), value))));
+    values.extend(cli.iter().cloned());
+    values
+}
+
+fn apply_per_monitor(args: &Args, config: &Config, state: &mut AppState, indexes: &[usize]) {
+    let modes = merged(&config.mode, &config.outputs, |o| &o.mode, &args.modes);
+    // [cycle] and [generator] each have their own default interval
+    let cycle_intervals = merged(&config.interval, &config.outputs, |o| &o.interval, &args.intervals);
+    let generator_intervals = merged(&config.generator.interval, &config.outputs, |o| &o.interval, &args.intervals);
+    let backgrounds = merged(&config.background, &config.outputs, |o| &o.background, &args.backgrounds);
+    let orders = merged(&config.order, &config.outputs, |o| &o.order, &args.orders);
+
+    for &index in indexes {
+        let mode = value_for(&modes, index, state).copied();
+        let intervals = match state.wallpapers[index].source {
+            Some(Source::Generated(_)) => &generator_intervals,
+            _ => &cycle_intervals,
+        };
+        let interval = value_for(intervals, index, state).copied();
+        let background = value_for(&backgrounds, index, state).cloned();
+        let order = value_for(&orders, index, state).copied();
+
+        let wallpaper = &mut state.wallpapers[index];
+        if let Some(mode) = mode {
+            wallpaper.scale_mode = mode;
+        }
+        if let Some(interval) = interval {
+            wallpaper.interval = Some(interval);
+        }
+        if let Some(background) = background {
+            wallpaper.background = background;
+        }
+        if let Some(order) = order {
+            wallpaper.order = order;
+        }
+    }
+}
+
+/// The config file given with --config, or the default one if it exists.
+fn config_path(args: &Args) -> Option<PathBuf> {
+    args.config.clone().or_else(|| default_config_path().filter(|path| path.exists()))
+}
+
+fn read_config(args: &Args) -> Config {
+    let Some(path) = config_path(args) else {
+        return Config::default();
+    };
+    load_config(&path).unwrap_or_else(|errors| {
+        report_errors(&path, &errors);
+        process::exit(1);
+    })
+}
+
+/// Command line flags that override settings from the config file.
+fn apply_args(args: &Args, config: &mut Config) {
+    if args.local {
+        config.generator.backends = vec!["local".to_string()];
+    }
+    if args.prompt.is_some() {
+        config.prompt.text = args.prompt.clone();
+    }
+    if let Some(kind) = args.transition {
+        config.transition.kind = kind;
+    }
+    if let Some(duration) = args.transition_duration {
+        config.transition.duration = duration;
+    }
+    if let Some(easing) = args.transition_easing {
+        config.transition.easing = easing;
     }
-    if args.image.is_some() {
-        return Ok(Mode::IMAGE(args.image.clone().unwrap()));
+    if let Some(loops) = args.loops {
+        config.animation.loops = loops;
     }
-    if args.cycle.is_some() {
-        let mut interval = 60 * 60; // Every hour
-        if args.interval.is_some() {
-           interval = args.interval.unwrap(); 
+    if let Some(max_fps) = args.max_fps {
+        config.animation.max_fps = max_fps;
+    }
+    if args.recursive || args.max_depth.is_some() {
+        config.scan.recursive = true;
+    }
+    if args.max_depth.is_some() {
+        config.scan.max_depth = args.max_depth;
+    }
+    if !args.includes.is_empty() {
+        config.scan.include = args.includes.clone();
+    }
+    if !args.excludes.is_empty() {
+        config.scan.exclude = args.excludes.clone();
+    }
+    config.scan.follow_symlinks |= args.follow_symlinks;
+    config.scan.hidden |= args.hidden;
+}
+
+fn check_config(args: &Args) -> i32 {
+    let Some(path) = args.config.clone().or_else(default_config_path) else {
+        eprintln!("Error: could not determine the config path, set XDG_CONFIG_HOME or HOME");
+        return 1;
+    };
+    match load_config(&path) {
+        Ok(_) => {
+            println!("{}: OK", path.display());
+            0
         }
-        return Ok(Mode::CYCLE(args.cycle.clone().unwrap(), Duration::new(interval, 0)));
+        Err(errors) => {
+            report_errors(&path, &errors);
+            1
+        }
+    }
+}
+
+/// Monitor selectors paired with the source they should show, in the order they apply.
+fn determine_assignments(args: &Args, config: &Config) -> Vec<(String, Source)> {
+    let mut assignments: Vec<_> = config.outputs.iter()
+        .filter_map(|o| o.source.clone().map(|source| (o.monitor.clone(), source)))
+        .collect();
+    if let Some(source) = determine_source(args) {
+        assignments.push((args.monitors.clone().unwrap_or("0".to_string()), source));
+    }
+    assignments.extend(args.outputs.iter().cloned());
+    assignments
+}
+
+fn determine_source(args: &Args) -> Option<Source> {
+    if let Some(fill) = &args.plain {
+        return Some(Source::Plain(fill.clone()));
+    }
+    if let Some(image) = &args.image {
+        return Some(Source::Image(image.clone()));
+    }
+    if !args.cycle.is_empty() {
+        return Some(Source::Cycle(args.cycle.clone()));
     }
     if args.generated {
-        let prompt = build_enriched_prompt(&args.prompt);
-        return Ok(Mode::GENERATED(prompt));
+        return Some(Source::Generated(args.prompt.clone()));
     }
-    Err("no mode found".to_string())
+    None
 }
 
-fn get_images_from_dir(path: &str) -> Vec<PathBuf> {
+/// Moves cycling wallpapers one image forward or back and restarts their interval.
+fn show_next(state: &mut AppState, qh: &QueueHandle<AppState>, indexes: &[usize], forward: bool) {
     let mut images = Vec::new();
-    
-    if let Ok(entries) = fs::read_dir(path) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                
-                if path.is_file() {
-                    if let Some(extension) = path.extension() {
-                        if let Some(ext_str) = extension.to_str() {
-                            match ext_str.to_lowercase().as_str() {
-                                "jpg" | "jpeg" | "png" | "webp" | "gif" | "bmp" => {
-                                    images.push(path);
-                                }
-                                _ => {}
-                            }
-                        }
+    for &index in indexes {
+        let wallpaper = &mut state.wallpapers[index];
+        let Some(playlist) = wallpaper.playlist.as_mut() else {
+            continue;
+        };
+        let img_path = if forward { playlist.advance() } else { playlist.back() };
+        let img_path = img_path.map(|p| p.to_string_lossy().into_owned());
+        wallpaper.schedule_switch();
+        if let Some(img_path) = img_path {
+            println!("Switching to {img_path}");
+            images.push((index, img_path));
+        }
+    }
+    show_images(state, qh, images);
+}
+
+fn handle_request(request: Request, args: &Args, config: &mut Config, state: &mut AppState, qh: &QueueHandle<AppState>) -> Response {
+    try_handle_request(request, args, config, state, qh).unwrap_or_else(Response::error)
+}
+
+fn try_handle_request(request: Request, args: &Args, config: &mut Config, state: &mut AppState, qh: &QueueHandle<AppState>) -> Result<Response, String> {
+    let select = |monitors: &Option<String>, state: &AppState| select_monitors(monitors.as_deref().unwrap_or("all"), state);
+
+    match request {
+        Request::Set { monitors, source } => {
+            let selected = select(&monitors, state)?;
+            let source = Source::parse(&source)?;
+            let previous: Vec<_> = selected.iter().map(|&index| {
+                let wallpaper = &mut state.wallpapers[index];
+                (index, wallpaper.source.replace(source.clone()), wallpaper.playlist.take(), (wallpaper.next_switch.take(), wallpaper.switch_at.take(), wallpaper.switch_due.take()))
+            }).collect();
+            let failures = apply_sources(state, qh, &selected);
+            for &index in selected.iter().filter(|&&index| failures.iter().all(|(failed, _)| *failed != index)) {
+                state.runtime_sources.insert(monitor_number(state, index), source.clone());
+            }
+            if !failures.is_empty() {
+                // The previous wallpaper is still on screen, so its source stays too
+                for (index, source, playlist, (next_switch, switch_at, switch_due)) in previous {
+                    if failures.iter().any(|(failed, _)| *failed == index) {
+                        let wallpaper = &mut state.wallpapers[index];
+                        wallpaper.source = source;
+                        wallpaper.playlist = playlist;
+                        wallpaper.next_switch = next_switch;
+                        wallpaper.switch_at = switch_at;
+                        wallpaper.switch_due = switch_due;
                     }
                 }
+                return Err(failures.iter()
+                    .map(|(index, e)| format!("{}: {e}", monitor_name(state, *index)))
+                    .collect::<Vec<_>>()
+                    .join("\n"));
+            }
+        }
+        Request::Next { monitors } => {
+            let selected = select(&monitors, state)?;
+            show_next(state, qh, &selected, true);
+            start_generation(state, qh, &selected, config);
+        }
+        Request::Prev { monitors } => {
+            let selected = select(&monitors, state)?;
+            show_next(state, qh, &selected, false);
+        }
+        Request::Pause { monitors } => {
+            for index in select(&monitors, state)? {
+                state.wallpapers[index].paused = true;
+            }
+        }
+        Request::Resume { monitors } => {
+            for index in select(&monitors, state)? {
+                let wallpaper = &mut state.wallpapers[index];
+                wallpaper.paused = false;
+                // A wallpaper that is being generated is scheduled once the image arrives
+                if !wallpaper.generating {
+                    wallpaper.schedule_switch();
+                }
             }
         }
+        Request::Status => return Ok(Response {
+            outputs: output_status(state),
+            generation: state.generator.as_ref().map(Generator::status),
+            ..Response::ok()
+        }),
+        Request::Reload => reload(args, config, state, qh)?,
     }
-    
-    images
+    Ok(Response::ok())
 }
 
-fn cycle_images(path: &str, interval: &Duration, state: &mut AppState, qh: &QueueHandle<AppState>, event_queue: &mut EventQueue<AppState>, conn: &Connection, monitors: Vec<usize>) {
-    let images = get_images_from_dir(path);
-    let mut curr_img_index = 0;
-    let mut next_switch_time = Instant::now();
-    loop {
-       let now = Instant::now(); 
-       if now >= next_switch_time {
-           let img_path = &images[curr_img_index].to_string_lossy().into_owned();
-           println!("Switching to {img_path}");
-           monitors.iter().for_each(|m_index| set_img(&state, &qh, &img_path, m_index.to_owned()));
-           curr_img_index = (curr_img_index + 1) % images.len();
-           next_switch_time = now + interval.to_owned();
-           let _ = conn.flush();
-       }
-        event_queue.dispatch_pending(state).unwrap();
-        std::thread::sleep(Duration::from_millis(100));
+/// The output name of a wallpaper, or its number if the compositor didn't name it.
+fn monitor_name(state: &AppState, index: usize) -> String {
+    state.output(&state.wallpapers[index].output).and_then(|o| o.name.clone()).unwrap_or_else(|| monitor_number(state, index).to_string())
+}
+
+fn output_status(state: &AppState) -> Vec<OutputStatus> {
+    let mut outputs: Vec<OutputStatus> = state.wallpapers.iter().enumerate().map(|(index, wallpaper)| {
+        let output = state.output(&wallpaper.output);
+        OutputStatus {
+            index: monitor_number(state, index),
+            name: output.and_then(|o| o.name.clone()),
+            description: output.and_then(|o| o.description.clone()),
+            width: wallpaper.width,
+            height: wallpaper.height,
+            scale: wallpaper.scale(),
+            source: wallpaper.source.as_ref().map(|s| s.to_string()),
+            current: wallpaper.playlist.as_ref()
+                .and_then(|p| p.current_image())
+                .map(|p| p.to_string_lossy().into_owned())
+                .or(wallpaper.generated.clone()),
+            paused: wallpaper.paused,
+            error: wallpaper.error.clone(),
+            quarantined: wallpaper.playlist.iter()
+                .flat_map(|p| p.quarantined())
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect(),
+            generating: wallpaper.generating,
+        }
+    }).collect();
+    // A monitor that was plugged in again comes after the others in `wallpapers`
+    outputs.sort_by_key(|output| output.index);
+    outputs
+}
+
+/// Re-reads the configuration file and applies it as if paber had just started.
+fn reload(args: &Args, config: &mut Config, state: &mut AppState, qh: &QueueHandle<AppState>) -> Result<(), String> {
+    let mut new_config = match config_path(args) {
+        Some(path) => load_config(&path).map_err(|errors| format_errors(&path, &errors).join("\n"))?,
+        None => Config::default(),
+    };
+    apply_args(args, &mut new_config);
+    *config = new_config;
+    state.transition = config.transition;
+    state.animation = config.animation;
+    state.scan = config.scan.clone();
+    state.home = paber_home(config);
+
+    state.wallpapers.iter_mut().for_each(|w| w.reset_settings());
+    state.runtime_sources.clear();
+    update_outputs(state, qh, args, config);
+    println!("Configuration reloaded");
+    Ok(())
+}
+
+/// Draws the source of `paber render` to a PNG file, with the scaling mode, background,
+/// cycle and generator settings a monitor of that size would get.
+fn render(args: &Args) -> Result<(), PaberError> {
+    let Some(Command::Render { size: (width, height), source, output, mode, background }) = &args.command else {
+        return Ok(());
+    };
+    let mut config = read_config(args);
+    apply_args(args, &mut config);
+    let mode = mode.or(config.mode).unwrap_or_default();
+    let background = background.clone().or(config.background.clone()).unwrap_or(Fill::Solid(Color::rgb(0, 0, 0)));
+
+    let image = match source {
+        Source::Plain(fill) => fill.render(*width, *height),
+        Source::Image(path) => render_image(Path::new(path), *width, *height, mode, &background)?,
+        Source::Cycle(dirs) => {
+            let mut playlist = Playlist::from_dirs(dirs, &config.scan, config.order.unwrap_or_default(), None);
+            let first = playlist.advance().ok_or_else(|| PaberError::NoImages(dirs.join(", ")))?;
+            render_image(first, *width, *height, mode, &background)?
+        }
+        Source::Generated(prompt) => {
+            let path = generate_for(prompt, (*width, *height), &config)?;
+            render_image(Path::new(&path), *width, *height, mode, &background)?
+        }
+    };
+    image.save_with_format(output, ImageFormat::Png)
+        .map_err(|e| PaberError::Write { path: output.clone(), message: e.to_string() })?;
+    println!("Rendered {}x{} to {}", width, height, output.display());
+    Ok(())
+}
+
+fn ctl(action: &CtlCommand) -> i32 {
+    let request = match action {
+        CtlCommand::Set { source, monitors } => {
+            // The daemon runs in another directory, so send absolute paths
+            let source = fs::canonicalize(source)
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or(source.clone());
+            Request::Set { monitors: monitors.clone(), source }
+        }
+        CtlCommand::Next { monitors } => Request::Next { monitors: monitors.clone() },
+        CtlCommand::Prev { monitors } => Request::Prev { monitors: monitors.clone() },
+        CtlCommand::Pause { monitors } => Request::Pause { monitors: monitors.clone() },
+        CtlCommand::Resume { monitors } => Request::Resume { monitors: monitors.clone() },
+        CtlCommand::Status => Request::Status,
+        CtlCommand::Reload => Request::Reload,
+    };
+
+    let response = match ipc::send(&request) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            return 1;
+        }
+    };
+    if let Some(error) = response.error {
+        eprintln!("Error: {error}");
+        return 1;
+    }
+    for output in response.outputs {
+        println!("{output}");
+    }
+    if let Some(generation) = response.generation
+        && generation.queued + generation.ready + generation.cancelled > 0
+    {
+        println!(
+            "generator: {} queued, {} ready, {} cancelled{}",
+            generation.queued,
+            generation.ready,
+            generation.cancelled,
+            generation.running.map(|r| format!(", generating {r}")).unwrap_or_default(),
+        );
     }
+    0
 }
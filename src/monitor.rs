@@ -0,0 +1,148 @@
+use glob::Pattern;
+
+use crate::state::{AppState, Output};
+
+/// Numbers monitors in the order they appear. A monitor that is unplugged keeps its
+/// number, so the others don't shift, and gets it back when it is plugged in again.
+#[derive(Debug, Default)]
+pub struct MonitorNumbers {
+    /// Connector name by number, `None` for outputs without one
+    names: Vec<Option<String>>,
+}
+
+impl MonitorNumbers {
+    /// The number of a monitor that was just described. `in_use` tells the numbers of
+    /// connected monitors, which aren't handed out twice.
+    pub fn assign(&mut self, name: Option<&str>, in_use: impl Fn(usize) -> bool) -> usize {
+        if let Some(name) = name
+            && let Some(number) = self.names.iter().position(|known| known.as_deref() == Some(name))
+            && !in_use(number)
+        {
+            return number;
+        }
+        self.names.push(name.map(str::to_string));
+        self.names.len() - 1
+    }
+}
+
+/// The number of the monitor a wallpaper is on, or its index if the output is gone.
+pub fn monitor_number(state: &AppState, index: usize) -> usize {
+    state.output(&state.wallpapers[index].output).and_then(|o| o.number).unwrap_or(index)
+}
+
+/// Resolves a comma separated monitor list into wallpaper indexes.
+///
+/// Each entry can be `all`, a monitor number, a connector name (`DP-1`) or a glob
+/// matched against the connector name and the description (`Dell*`).
+pub fn select_monitors(spec: &str, state: &AppState) -> Result<Vec<usize>, String> {
+    let mut selected = Vec::new();
+
+    for token in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let matches: Vec<usize> = (0..state.wallpapers.len())
+            .filter(|&index| matches_monitor(token, index, state))
+            .collect();
+
+        if matches.is_empty() {
+            return Err(format!("No monitor matches '{token}'. Available monitors:\n{}", describe_monitors(state)));
+        }
+        for index in matches {
+            if !selected.contains(&index) {
+                selected.push(index);
+            }
+        }
+    }
+
+    if selected.is_empty() {
+        return Err("No monitors given".to_string());
+    }
+    Ok(selected)
+}
+
+/// Checks a selector given at startup. Indexes have to exist, while names and
+/// globs may refer to monitors that are only plugged in later.
+pub fn check_monitors(spec: &str, state: &AppState) -> Result<(), String> {
+    for token in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if (0..state.wallpapers.len()).any(|index| matches_monitor(token, index, state)) {
+            continue;
+        }
+        if token.parse::<usize>().is_ok() {
+            // Monitors are numbered as they appear, so at startup every number exists
+            return Err(format!("Monitor {token} does not exist. Available monitors:\n{}", describe_monitors(state)));
+        }
+        println!("No monitor matches '{token}' yet, it will be used once it is connected");
+    }
+    Ok(())
+}
+
+/// Whether any entry of a comma separated selector refers to the wallpaper at `index`.
+pub fn spec_matches(spec: &str, index: usize, state: &AppState) -> bool {
+    spec.split(',').map(str::trim).any(|token| matches_monitor(token, index, state))
+}
+
+/// Whether a single selector token refers to the wallpaper at `index`.
+pub fn matches_monitor(token: &str, index: usize, state: &AppState) -> bool {
+    if token.eq_ignore_ascii_case("all") {
+        return true;
+    }
+    let Some(output) = state.wallpapers.get(index).and_then(|w| state.output(&w.output)) else {
+        return false;
+    };
+    if let Ok(wanted) = token.parse::<usize>() {
+        return output.number == Some(wanted);
+    }
+    if output.name.as_deref() == Some(token) {
+        return true;
+    }
+    let Ok(pattern) = Pattern::new(token) else {
+        return false;
+    };
+    [&output.name, &output.description]
+        .into_iter()
+        .flatten()
+        .any(|field| pattern.matches(field))
+}
+
+pub fn describe_monitors(state: &AppState) -> String {
+    state.wallpapers.iter().enumerate()
+        .map(|(index, wallpaper)| {
+            let (name, description) = state.output(&wallpaper.output)
+                .map(describe_output)
+                .unwrap_or(("unknown", ""));
+            format!("  {}: {name} {description}", monitor_number(state, index))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn describe_output(output: &Output) -> (&str, &str) {
+    (
+        output.name.as_deref().unwrap_or("unknown"),
+        output.description.as_deref().unwrap_or(""),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_monitors_in_order() {
+        let mut numbers = MonitorNumbers::default();
+        assert_eq!(numbers.assign(Some("DP-1"), |_| false), 0);
+        assert_eq!(numbers.assign(Some("HDMI-A-1"), |n| n == 0), 1);
+        assert_eq!(numbers.assign(None, |n| n <= 1), 2);
+        assert_eq!(numbers.assign(None, |n| n <= 2), 3);
+    }
+
+    #[test]
+    fn gives_a_monitor_its_number_back() {
+        let mut numbers = MonitorNumbers::default();
+        numbers.assign(Some("DP-1"), |_| false);
+        numbers.assign(Some("DP-2"), |n| n == 0);
+        // DP-1 is unplugged, DP-2 keeps number 1 and DP-1 gets 0 back
+        assert_eq!(numbers.assign(Some("DP-1"), |n| n == 1), 0);
+        // A second output with the same name doesn't take a number in use
+        assert_eq!(numbers.assign(Some("DP-1"), |n| n <= 1), 2);
+        assert_eq!(numbers.assign(Some("DP-3"), |_| false), 3);
+    }
+}
@@ -0,0 +1,707 @@
+use std::{cmp::Ordering, collections::{HashMap, HashSet}, fmt, fs, path::{Path, PathBuf}, str::FromStr, time::{Instant, SystemTime}};
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+/// The order in which a cycle source goes through its images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// By file name
+    #[default]
+    Name,
+    /// By file name, comparing numbers by value so `2.png` comes before `10.png`
+    Natural,
+    /// Most recently modified first
+    Mtime,
+    /// Any image at random every time
+    Random,
+    /// Every image once in random order, reshuffled when all were shown
+    Shuffle,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "name" => Ok(Order::Name),
+            "natural" => Ok(Order::Natural),
+            "mtime" => Ok(Order::Mtime),
+            "random" => Ok(Order::Random),
+            "shuffle" => Ok(Order::Shuffle),
+            other => Err(format!("unknown order '{other}' (expected name, natural, mtime, random or shuffle)")),
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Order::Name => "name",
+            Order::Natural => "natural",
+            Order::Mtime => "mtime",
+            Order::Random => "random",
+            Order::Shuffle => "shuffle",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Images of a cycle source and the one currently shown.
+pub struct Playlist {
+    images: Vec<PathBuf>,
+    current: Option<usize>,
+    order: Order,
+    /// The directories of the source
+    roots: Vec<String>,
+    /// Every directory the last scan went through, watched for changes
+    scanned: Vec<PathBuf>,
+    /// Where the position is kept across restarts
+    store: Option<PlaylistStore>,
+    /// A watched directory changed, the images are scanned again at this time
+    pub rescan_at: Option<Instant>,
+    /// Images that failed to load and their modification time then. They are
+    /// skipped until the file changes
+    quarantined: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl Playlist {
+    /// Scans `dirs` and sorts their images. With a `store`, the playlist continues
+    /// from the position saved there by the previous run.
+    pub fn from_dirs(dirs: &[String], options: &ScanOptions, order: Order, store: Option<PlaylistStore>) -> Self {
+        let (images, scanned) = scan_dirs(dirs, options);
+        let mut playlist = Playlist { images, current: None, order, roots: dirs.to_vec(), scanned, store, rescan_at: None, quarantined: HashMap::new() };
+        playlist.sort();
+        if let Some(saved) = playlist.store.as_ref().and_then(|store| store.load()) {
+            playlist.restore(saved);
+        }
+        playlist
+    }
+
+    /// Moves to the next image and returns it.
+    pub fn advance(&mut self) -> Option<&PathBuf> {
+        self.step(true)
+    }
+
+    /// Moves to the previous image and returns it.
+    pub fn back(&mut self) -> Option<&PathBuf> {
+        self.step(false)
+    }
+
+    /// Scans the directories again after they changed, keeping the current
+    /// image and the shuffle round.
+    pub fn rescan(&mut self, options: &ScanOptions) {
+        self.rescan_at = None;
+        let (images, scanned) = scan_dirs(&self.roots, options);
+        self.scanned = scanned;
+        let previous = std::mem::replace(&mut self.images, images);
+        self.sort();
+        if self.order == Order::Shuffle {
+            self.keep_round(previous.clone());
+        }
+
+        // If the current image was removed, the next switch continues after the closest one before it
+        let positions: HashMap<&PathBuf, usize> = self.images.iter().enumerate().map(|(i, path)| (path, i)).collect();
+        self.current = self.current
+            .and_then(|current| previous.get(..=current))
+            .and_then(|before| before.iter().rev().find_map(|path| positions.get(path).copied()));
+        self.quarantined.retain(|path, _| positions.contains_key(path));
+        println!("Rescanned {}: {} images", self.roots.join(", "), self.images.len());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Skips `path` from now on, until it is modified.
+    pub fn quarantine(&mut self, path: &Path) {
+        println!("Quarantined {}, it is skipped until it changes", path.display());
+        self.quarantined.insert(path.to_path_buf(), modified(path));
+    }
+
+    /// Quarantined images by path.
+    pub fn quarantined(&self) -> Vec<&PathBuf> {
+        let mut quarantined: Vec<&PathBuf> = self.quarantined.keys().collect();
+        quarantined.sort();
+        quarantined
+    }
+
+    /// Directories to watch for changes.
+    pub fn dirs(&self) -> impl Iterator<Item = &Path> {
+        self.scanned.iter().map(PathBuf::as_path)
+    }
+
+    /// Whether `dir` is one of the directories the images come from.
+    pub fn contains_dir(&self, dir: &Path) -> bool {
+        self.scanned.iter().any(|scanned| scanned == dir)
+    }
+
+    pub fn current_image(&self) -> Option<&PathBuf> {
+        self.images.get(self.current?)
+    }
+
+    /// Moves one image forward or back, passing over files that were removed since
+    /// the last scan and quarantined ones.
+    fn step(&mut self, forward: bool) -> Option<&PathBuf> {
+        for _ in 0..self.images.len() {
+            let index = self.next_index(forward)?;
+            self.current = Some(index);
+            let path = &self.images[index];
+            if !path.is_file() {
+                println!("Skipping {}, it doesn't exist anymore", path.display());
+                continue;
+            }
+            if let Some(&quarantined_at) = self.quarantined.get(path) {
+                if modified(path) == quarantined_at {
+                    continue;
+                }
+                // Replaced by a new version, which gets another chance
+                self.quarantined.remove(&self.images[index]);
+            }
+            return self.go_to(index);
+        }
+        None
+    }
+
+    fn next_index(&mut self, forward: bool) -> Option<usize> {
+        let len = self.images.len();
+        let index = match (self.order, self.current) {
+            (_, _) if len == 0 => return None,
+            (Order::Random, current) => random_other(len, current),
+            (Order::Shuffle, Some(current)) if forward && current + 1 == len => {
+                self.reshuffle();
+                0
+            }
+            (_, Some(current)) if forward => (current + 1) % len,
+            (_, Some(current)) => (current + len - 1) % len,
+            (_, None) => 0,
+        };
+        Some(index)
+    }
+
+    fn go_to(&mut self, index: usize) -> Option<&PathBuf> {
+        self.current = Some(index);
+        if let Some(store) = &self.store
+            && let Err(e) = store.save(self)
+        {
+            eprintln!("Failed to save the playlist position: {e:#}");
+        }
+        self.images.get(index)
+    }
+
+    fn sort(&mut self) {
+        match self.order {
+            Order::Name | Order::Random => self.images.sort(),
+            Order::Natural => self.images.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy())),
+            Order::Mtime => self.images.sort_by_cached_key(|path| std::cmp::Reverse(modified(path))),
+            Order::Shuffle => self.images.shuffle(&mut rand::rng()),
+        }
+    }
+
+    /// Starts a new round of shuffle, making sure the image on screen doesn't come up first.
+    fn reshuffle(&mut self) {
+        let last = self.current_image().cloned();
+        self.images.shuffle(&mut rand::rng());
+        if self.images.len() > 1 && self.images.first() == last.as_ref() {
+            let swap = rand::random_range(1..self.images.len());
+            self.images.swap(0, swap);
+        }
+    }
+
+    fn restore(&mut self, saved: SavedPlaylist) {
+        if self.order == Order::Shuffle && saved.order == Order::Shuffle && !saved.queue.is_empty() {
+            self.keep_round(saved.queue);
+        }
+        self.current = saved.current.and_then(|current| self.images.iter().position(|path| *path == current));
+    }
+
+    /// Puts the images back in the order of `round`, with images that aren't part of it at the end.
+    fn keep_round(&mut self, round: Vec<PathBuf>) {
+        let existing: HashSet<&PathBuf> = self.images.iter().collect();
+        let mut queue: Vec<PathBuf> = round.into_iter().filter(|path| existing.contains(path)).collect();
+        let queued: HashSet<PathBuf> = queue.iter().cloned().collect();
+        queue.extend(self.images.iter().filter(|path| !queued.contains(*path)).cloned());
+        self.images = queue;
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Any index below `len` other than `current`, unless there is only one.
+fn random_other(len: usize, current: Option<usize>) -> usize {
+    match current {
+        Some(current) if len > 1 => (current + rand::random_range(1..len)) % len,
+        _ => rand::random_range(0..len),
+    }
+}
+
+/// Compares file names like a person would, with runs of digits compared as numbers.
+/// Case and leading zeros only decide between names that are otherwise equal.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    let mut tie = Ordering::Equal;
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return tie,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(c);
+                    }
+                    digits
+                };
+                let (x, y) = (number(&mut a), number(&mut b));
+                let (x_trimmed, y_trimmed) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = x_trimmed.len().cmp(&y_trimmed.len())
+                    .then_with(|| x_trimmed.cmp(y_trimmed));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                tie = tie.then(x.len().cmp(&y.len()));
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                tie = tie.then(x.cmp(&y));
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Extensions of the files a cycle source shows when no include patterns are given
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "gif", "bmp"];
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+/// Which files in the directories of a cycle source are shown.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScanOptions {
+    /// Descend into subdirectories
+    pub recursive: bool,
+    /// Levels of subdirectories to descend into, `None` for no limit
+    pub max_depth: Option<usize>,
+    /// Files matching one of these are shown instead of those with an image extension
+    pub include: Vec<Pattern>,
+    /// Files and directories matching one of these are skipped
+    pub exclude: Vec<Pattern>,
+    /// Descend into symlinked directories. Symlinked files are always shown
+    pub follow_symlinks: bool,
+    /// Include files and directories whose name starts with a dot
+    pub hidden: bool,
+}
+
+/// Parses an include or exclude pattern, matched against paths relative to the cycled directory.
+pub fn parse_glob(s: &str) -> Result<Pattern, String> {
+    Pattern::new(s.trim()).map_err(|e| format!("invalid pattern '{}': {e}", s.trim()))
+}
+
+/// Collects the images of every directory without duplicates, and the directories that were read.
+fn scan_dirs(dirs: &[String], options: &ScanOptions) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut images = Vec::new();
+    let mut scanned = Vec::new();
+    for dir in dirs {
+        let root = Path::new(dir);
+        // Directories that were scanned already, so symlinks can't lead into a loop
+        let mut visited: HashSet<PathBuf> = fs::canonicalize(root).into_iter().collect();
+        scan_dir(root, root, 0, options, &mut visited, &mut images, &mut scanned);
+    }
+    images.sort();
+    images.dedup();
+    (images, scanned)
+}
+
+fn scan_dir(root: &Path, dir: &Path, depth: usize, options: &ScanOptions, visited: &mut HashSet<PathBuf>, images: &mut Vec<PathBuf>, scanned: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", dir.display());
+            return;
+        }
+    };
+    scanned.push(dir.to_path_buf());
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !options.hidden && entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if options.exclude.iter().any(|pattern| pattern.matches_path_with(relative, MATCH_OPTIONS)) {
+            continue;
+        }
+        let is_symlink = entry.file_type().is_ok_and(|t| t.is_symlink());
+        // Follows symlinks, broken ones are skipped
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            if !options.recursive
+                || options.max_depth.is_some_and(|max| depth >= max)
+                || (is_symlink && !options.follow_symlinks)
+            {
+                continue;
+            }
+            if let Ok(canonical) = fs::canonicalize(&path)
+                && visited.insert(canonical)
+            {
+                scan_dir(root, &path, depth + 1, options, visited, images, scanned);
+            }
+        } else if metadata.is_file() && is_wanted(relative, options) {
+            images.push(path);
+        }
+    }
+}
+
+fn is_wanted(relative: &Path, options: &ScanOptions) -> bool {
+    if !options.include.is_empty() {
+        return options.include.iter().any(|pattern| pattern.matches_path_with(relative, MATCH_OPTIONS));
+    }
+    relative.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Positions of every playlist, saved as `playlists.json` in PABER_HOME.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedPlaylists {
+    playlists: Vec<SavedPlaylist>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedPlaylist {
+    monitor: String,
+    directories: Vec<String>,
+    order: Order,
+    current: Option<PathBuf>,
+    /// The current round of shuffle
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    queue: Vec<PathBuf>,
+}
+
+/// Where a playlist of one monitor saves its position.
+pub struct PlaylistStore {
+    file: PathBuf,
+    monitor: String,
+    directories: Vec<String>,
+}
+
+impl PlaylistStore {
+    pub fn new(home: &Path, monitor: String, directories: Vec<String>) -> Self {
+        PlaylistStore { file: home.join("playlists.json"), monitor, directories }
+    }
+
+    fn load(&self) -> Option<SavedPlaylist> {
+        read_saved(&self.file).ok()?.playlists.into_iter()
+            .find(|saved| saved.monitor == self.monitor && saved.directories == self.directories)
+    }
+
+    fn save(&self, playlist: &Playlist) -> Result<()> {
+        // Other monitors save to the same file, a broken one is started over
+        let mut saved = read_saved(&self.file).unwrap_or_default();
+        saved.playlists.retain(|saved| saved.monitor != self.monitor || saved.directories != self.directories);
+        saved.playlists.push(SavedPlaylist {
+            monitor: self.monitor.clone(),
+            directories: self.directories.clone(),
+            order: playlist.order,
+            current: playlist.current_image().cloned(),
+            queue: if playlist.order == Order::Shuffle { playlist.images.clone() } else { Vec::new() },
+        });
+
+        if let Some(parent) = self.file.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        // Written next to the file and renamed, so a crash never leaves half of it
+        let temporary = self.file.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_string_pretty(&saved)?)
+            .with_context(|| format!("Failed to write {}", temporary.display()))?;
+        fs::rename(&temporary, &self.file).with_context(|| format!("Failed to write {}", self.file.display()))?;
+        Ok(())
+    }
+}
+
+fn read_saved(file: &Path) -> Result<SavedPlaylists> {
+    Ok(serde_json::from_str(&fs::read_to_string(file)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::symlink, process};
+
+    /// A directory under the system's temporary directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("paber-test-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn with_files(self, files: &[&str]) -> Self {
+            self.write(files);
+            self
+        }
+
+        /// Creates the files at the given relative paths, with their parent directories.
+        fn write(&self, files: &[&str]) {
+            for file in files {
+                let path = self.0.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, file).unwrap();
+            }
+        }
+
+        fn dirs(&self) -> Vec<String> {
+            vec![self.0.to_string_lossy().into_owned()]
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const FILES: &[&str] = &["a.png", "b.JPG", "notes.txt", ".hidden.png", ".dir/c.png", "sub/d.png", "sub/deeper/e.gif"];
+
+    /// The images found in `dir`, relative to it.
+    fn scan(dir: &TempDir, options: ScanOptions) -> Vec<String> {
+        let (images, _) = scan_dirs(&dir.dirs(), &options);
+        images.iter().map(|path| path.strip_prefix(&dir.0).unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    fn globs(patterns: &[&str]) -> Vec<Pattern> {
+        patterns.iter().map(|pattern| parse_glob(pattern).unwrap()).collect()
+    }
+
+    #[test]
+    fn finds_images_in_the_directory_only() {
+        let dir = TempDir::new("flat").with_files(FILES);
+        assert_eq!(scan(&dir, ScanOptions::default()), ["a.png", "b.JPG"]);
+        let (_, scanned) = scan_dirs(&dir.dirs(), &ScanOptions::default());
+        assert_eq!(scanned, [dir.0.as_path()]);
+    }
+
+    #[test]
+    fn descends_into_subdirectories_up_to_max_depth() {
+        let dir = TempDir::new("recursive").with_files(FILES);
+        let recursive = ScanOptions { recursive: true, ..Default::default() };
+        assert_eq!(scan(&dir, recursive.clone()), ["a.png", "b.JPG", "sub/d.png", "sub/deeper/e.gif"]);
+        let (_, mut scanned) = scan_dirs(&dir.dirs(), &recursive);
+        scanned.sort();
+        assert_eq!(scanned, [dir.0.clone(), dir.0.join("sub"), dir.0.join("sub/deeper")]);
+
+        assert_eq!(scan(&dir, ScanOptions { max_depth: Some(1), ..recursive.clone() }), ["a.png", "b.JPG", "sub/d.png"]);
+        assert_eq!(scan(&dir, ScanOptions { max_depth: Some(0), ..recursive }), ["a.png", "b.JPG"]);
+    }
+
+    #[test]
+    fn includes_hidden_files_when_asked() {
+        let dir = TempDir::new("hidden").with_files(FILES);
+        let options = ScanOptions { hidden: true, recursive: true, max_depth: Some(1), ..Default::default() };
+        assert_eq!(scan(&dir, options), [".dir/c.png", ".hidden.png", "a.png", "b.JPG", "sub/d.png"]);
+    }
+
+    #[test]
+    fn matches_globs_against_the_relative_path_ignoring_case() {
+        let dir = TempDir::new("globs").with_files(FILES);
+        let recursive = ScanOptions { recursive: true, ..Default::default() };
+
+        let include = ScanOptions { include: globs(&["SUB/*.PNG", "*.txt"]), ..recursive.clone() };
+        assert_eq!(scan(&dir, include), ["notes.txt", "sub/d.png"]);
+
+        // An excluded directory isn't descended into
+        let exclude = ScanOptions { exclude: globs(&["a.*", "sub/DEEPER"]), ..recursive.clone() };
+        assert_eq!(scan(&dir, exclude), ["b.JPG", "sub/d.png"]);
+
+        let both = ScanOptions { include: globs(&["*.png"]), exclude: globs(&["sub"]), ..recursive };
+        assert_eq!(scan(&dir, both), ["a.png"]);
+    }
+
+    #[test]
+    fn follows_symlinked_directories_only_when_asked() {
+        let dir = TempDir::new("symlinks").with_files(&["a.png"]);
+        let elsewhere = TempDir::new("symlinks-target").with_files(&["f.png"]);
+        symlink(&elsewhere.0, dir.0.join("link")).unwrap();
+        symlink(dir.0.join("a.png"), dir.0.join("linked.png")).unwrap();
+        symlink(dir.0.join("missing.png"), dir.0.join("broken.png")).unwrap();
+        // Leads back to the cycled directory
+        symlink(&dir.0, elsewhere.0.join("loop")).unwrap();
+
+        let recursive = ScanOptions { recursive: true, ..Default::default() };
+        assert_eq!(scan(&dir, recursive.clone()), ["a.png", "linked.png"]);
+        let follow = ScanOptions { follow_symlinks: true, ..recursive };
+        assert_eq!(scan(&dir, follow.clone()), ["a.png", "link/f.png", "linked.png"]);
+        let (_, scanned) = scan_dirs(&dir.dirs(), &follow);
+        assert_eq!(scanned.len(), 2);
+    }
+
+    #[test]
+    fn lists_images_of_several_directories_once() {
+        let dir = TempDir::new("several").with_files(&["a.png", "sub/b.png"]);
+        let dirs = [dir.dirs(), vec![dir.0.join("sub").to_string_lossy().into_owned()]].concat();
+        let (images, _) = scan_dirs(&dirs, &ScanOptions { recursive: true, ..Default::default() });
+        assert_eq!(images, [dir.0.join("a.png"), dir.0.join("sub/b.png")]);
+    }
+
+    fn playlist(dir: &TempDir, order: Order) -> Playlist {
+        Playlist::from_dirs(&dir.dirs(), &ScanOptions::default(), order, None)
+    }
+
+    /// File names of the images `steps` go to.
+    fn walk(playlist: &mut Playlist, steps: &[bool]) -> Vec<String> {
+        steps.iter()
+            .map(|&forward| playlist.step(forward).map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn skips_quarantined_images() {
+        let dir = TempDir::new("quarantine").with_files(&["a.png", "b.png", "c.png"]);
+        let mut playlist = playlist(&dir, Order::Name);
+        assert_eq!(walk(&mut playlist, &[true]), ["a.png"]);
+
+        playlist.quarantine(&dir.0.join("b.png"));
+        assert_eq!(playlist.quarantined(), [&dir.0.join("b.png")]);
+        assert_eq!(walk(&mut playlist, &[true, false, true, true]), ["c.png", "a.png", "c.png", "a.png"]);
+        assert_eq!(playlist.advance(), Some(&dir.0.join("c.png")));
+        assert_eq!(playlist.back(), Some(&dir.0.join("a.png")));
+
+        playlist.quarantine(&dir.0.join("a.png"));
+        playlist.quarantine(&dir.0.join("c.png"));
+        assert_eq!(playlist.quarantined(), [&dir.0.join("a.png"), &dir.0.join("b.png"), &dir.0.join("c.png")]);
+        assert_eq!(playlist.advance(), None);
+    }
+
+    #[test]
+    fn gives_quarantined_images_another_chance_once_they_change() {
+        let dir = TempDir::new("quarantine-changed").with_files(&["a.png", "b.png", "c.png"]);
+        let mut playlist = playlist(&dir, Order::Name);
+        playlist.quarantine(&dir.0.join("b.png"));
+        assert_eq!(walk(&mut playlist, &[true, true]), ["a.png", "c.png"]);
+
+        let b = fs::File::options().write(true).open(dir.0.join("b.png")).unwrap();
+        b.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000)).unwrap();
+        assert_eq!(walk(&mut playlist, &[false]), ["b.png"]);
+        assert!(playlist.quarantined().is_empty());
+    }
+
+    #[test]
+    fn forgets_the_quarantine_of_removed_images() {
+        let dir = TempDir::new("quarantine-removed").with_files(&["a.png", "b.png"]);
+        let mut playlist = playlist(&dir, Order::Name);
+        playlist.quarantine(&dir.0.join("a.png"));
+        playlist.quarantine(&dir.0.join("b.png"));
+        fs::remove_file(dir.0.join("b.png")).unwrap();
+        playlist.rescan(&ScanOptions::default());
+        assert_eq!(playlist.quarantined(), [&dir.0.join("a.png")]);
+    }
+
+    fn name(path: Option<&PathBuf>) -> Option<String> {
+        path.map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+    }
+
+    /// A playlist of `files` showing `current`.
+    fn playlist_at(name: &str, files: &[&str], current: &str) -> (TempDir, Playlist) {
+        let dir = TempDir::new(name).with_files(files);
+        let mut playlist = playlist(&dir, Order::Name);
+        while self::name(playlist.current_image()).as_deref() != Some(current) {
+            playlist.advance();
+        }
+        (dir, playlist)
+    }
+
+    #[test]
+    fn keeps_the_position_when_images_are_added() {
+        let (dir, mut playlist) = playlist_at("rescan-added", &["b.png", "d.png"], "b.png");
+        dir.write(&["a.png", "c.png", "e.png"]);
+        playlist.rescan(&ScanOptions::default());
+        assert_eq!(name(playlist.current_image()).as_deref(), Some("b.png"));
+        assert_eq!(walk(&mut playlist, &[true, true, true, true]), ["c.png", "d.png", "e.png", "a.png"]);
+    }
+
+    #[test]
+    fn keeps_the_position_when_images_are_removed() {
+        let (dir, mut playlist) = playlist_at("rescan-removed", &["a.png", "b.png", "c.png", "d.png"], "c.png");
+        fs::remove_file(dir.0.join("a.png")).unwrap();
+        playlist.rescan(&ScanOptions::default());
+        assert_eq!(name(playlist.current_image()).as_deref(), Some("c.png"));
+
+        // Without the current image, the next switch continues after the one before it
+        fs::remove_file(dir.0.join("c.png")).unwrap();
+        playlist.rescan(&ScanOptions::default());
+        assert_eq!(name(playlist.current_image()).as_deref(), Some("b.png"));
+        assert_eq!(walk(&mut playlist, &[true, true]), ["d.png", "b.png"]);
+    }
+
+    #[test]
+    fn keeps_the_position_when_images_are_renamed() {
+        let (dir, mut playlist) = playlist_at("rescan-renamed", &["a.png", "b.png", "c.png"], "b.png");
+        fs::rename(dir.0.join("a.png"), dir.0.join("d.png")).unwrap();
+        playlist.rescan(&ScanOptions::default());
+        assert_eq!(name(playlist.current_image()).as_deref(), Some("b.png"));
+        assert_eq!(walk(&mut playlist, &[true, true]), ["c.png", "d.png"]);
+
+        // The renamed current image counts as removed
+        fs::rename(dir.0.join("d.png"), dir.0.join("0.png")).unwrap();
+        playlist.rescan(&ScanOptions::default());
+        assert_eq!(name(playlist.current_image()).as_deref(), Some("c.png"));
+        assert_eq!(walk(&mut playlist, &[true]), ["0.png"]);
+    }
+
+    #[test]
+    fn keeps_the_shuffle_round_when_images_are_added() {
+        let dir = TempDir::new("rescan-shuffle").with_files(&["a.png", "b.png", "c.png", "d.png"]);
+        let mut playlist = playlist(&dir, Order::Shuffle);
+        let first = walk(&mut playlist, &[true, true]);
+        let round = playlist.images.clone();
+        dir.write(&["e.png"]);
+        playlist.rescan(&ScanOptions::default());
+        assert_eq!(playlist.images[..4], round);
+        assert_eq!(name(playlist.current_image()), first.last().cloned());
+        let rest = walk(&mut playlist, &[true, true, true]);
+        assert_eq!(rest.last().map(String::as_str), Some("e.png"));
+    }
+
+    #[test]
+    fn numbers_compare_by_value() {
+        let mut names = vec!["img10.png", "img2.png", "img1.png", "img02.png", "Img3.png", "img.png"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["img.png", "img1.png", "img2.png", "img02.png", "Img3.png", "img10.png"]);
+    }
+
+    #[test]
+    fn long_numbers_do_not_overflow() {
+        assert_eq!(natural_cmp("99999999999999999999999", "100000000000000000000000"), Ordering::Less);
+        assert_eq!(natural_cmp("a00000000000000000000001", "a2"), Ordering::Less);
+    }
+
+    #[test]
+    fn case_only_breaks_ties() {
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("Apple", "apple"), Ordering::Less);
+        assert_eq!(natural_cmp("same", "same"), Ordering::Equal);
+        assert_eq!(natural_cmp("shot 9 b", "shot 9 a"), Ordering::Greater);
+        assert_eq!(natural_cmp("Img3.png", "img.png"), Ordering::Greater);
+        assert_eq!(natural_cmp("img02b", "img2a"), Ordering::Greater);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{path::Path, str::FromStr};
+
+use image::{DynamicImage, Rgba, RgbaImage, imageops::FilterType};
+
+use crate::{color::Fill, error::PaberError};
+
+/// How an image is fitted onto an output whose aspect ratio differs from its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ScaleMode {
+    /// Scale to cover the whole output, cropping the overflow
+    #[default]
+    Fill,
+    /// Scale to fit inside the output, letterboxing with the background
+    Fit,
+    /// Keep the original size, centered on the background
+    Center,
+    /// Repeat the image at its original size from the top left corner
+    Tile,
+    /// Scale to the exact output size, ignoring the aspect ratio
+    Stretch,
+}
+
+impl FromStr for ScaleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fill" => Ok(ScaleMode::Fill),
+            "fit" => Ok(ScaleMode::Fit),
+            "center" => Ok(ScaleMode::Center),
+            "tile" => Ok(ScaleMode::Tile),
+            "stretch" => Ok(ScaleMode::Stretch),
+            other => Err(format!("unknown scaling mode '{other}' (expected fill, fit, center, tile or stretch)")),
+        }
+    }
+}
+
+/// Opens the image at `path` and draws it on a `width`x`height` canvas, the way
+/// it appears on an output with that buffer size. Animations show their first frame.
+pub fn render_image(path: &Path, width: u32, height: u32, mode: ScaleMode, background: &Fill) -> Result<RgbaImage, PaberError> {
+    if width == 0 || height == 0 {
+        return Err(PaberError::EmptySize);
+    }
+    let img = image::open(path).map_err(|e| PaberError::Image { path: path.to_path_buf(), message: e.to_string() })?;
+    Ok(scale_image(&img, width, height, mode, background))
+}
+
+/// Whether every pixel of `image` is fully opaque.
+pub fn is_opaque(image: &RgbaImage) -> bool {
+    image.pixels().all(|pixel| pixel.0[3] == 255)
+}
+
+/// Scales `img` onto a `width`x`height` canvas according to `mode`.
+/// Areas the image doesn't cover or that are transparent show `background`.
+pub fn scale_image(img: &DynamicImage, width: u32, height: u32, mode: ScaleMode, background: &Fill) -> RgbaImage {
+    match mode {
+        ScaleMode::Stretch => over_background(background, img.resize_exact(width, height, FilterType::Triangle).to_rgba8()),
+        ScaleMode::Fill => over_background(background, img.resize_to_fill(width, height, FilterType::Triangle).to_rgba8()),
+        ScaleMode::Fit => {
+            let resized = img.resize(width, height, FilterType::Triangle).to_rgba8();
+            centered_on(background, width, height, &resized)
+        }
+        ScaleMode::Center => centered_on(background, width, height, &img.to_rgba8()),
+        ScaleMode::Tile => {
+            let tile = img.to_rgba8();
+            let mut canvas = background.render(width, height);
+            for y in (0..height).step_by(tile.height().max(1) as usize) {
+                for x in (0..width).step_by(tile.width().max(1) as usize) {
+                    overlay(&mut canvas, &tile, x as i64, y as i64);
+                }
+            }
+            canvas
+        }
+    }
+}
+
+/// Composites an image that covers the whole canvas over the background, if it has any transparency.
+fn over_background(background: &Fill, img: RgbaImage) -> RgbaImage {
+    if is_opaque(&img) {
+        return img;
+    }
+    let mut canvas = background.render(img.width(), img.height());
+    overlay(&mut canvas, &img, 0, 0);
+    canvas
+}
+
+fn centered_on(background: &Fill, width: u32, height: u32, img: &RgbaImage) -> RgbaImage {
+    let mut canvas = background.render(width, height);
+    let x = (width as i64 - img.width() as i64) / 2;
+    let y = (height as i64 - img.height() as i64) / 2;
+    overlay(&mut canvas, img, x, y);
+    canvas
+}
+
+/// Draws `img` over `canvas` with its top left corner at `x`, `y`, using the
+/// source-over operator on straight alpha.
+fn overlay(canvas: &mut RgbaImage, img: &RgbaImage, x: i64, y: i64) {
+    for (ix, iy, &Rgba(src)) in img.enumerate_pixels() {
+        let (cx, cy) = (x + ix as i64, y + iy as i64);
+        if cx < 0 || cy < 0 || cx >= canvas.width() as i64 || cy >= canvas.height() as i64 {
+            continue;
+        }
+        let dst = canvas.get_pixel_mut(cx as u32, cy as u32);
+        if src[3] == 255 || dst.0[3] == 0 {
+            *dst = Rgba(src);
+            continue;
+        }
+        let (sa, da) = (src[3] as f32 / 255.0, dst.0[3] as f32 / 255.0);
+        let alpha = sa + da * (1.0 - sa);
+        if alpha == 0.0 {
+            continue;
+        }
+        let mix = |s: u8, d: u8| ((s as f32 * sa + d as f32 * da * (1.0 - sa)) / alpha).round() as u8;
+        *dst = Rgba([mix(src[0], dst.0[0]), mix(src[1], dst.0[1]), mix(src[2], dst.0[2]), (alpha * 255.0).round() as u8]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    fn solid(width: u32, height: u32, pixel: Rgba<u8>) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, pixel))
+    }
+
+    fn blue_background() -> Fill {
+        Fill::Solid(Color::rgb(0, 0, 255))
+    }
+
+    #[test]
+    fn fill_covers_the_canvas() {
+        let scaled = scale_image(&solid(4, 2, RED), 3, 3, ScaleMode::Fill, &blue_background());
+        assert_eq!(scaled.dimensions(), (3, 3));
+        assert!(scaled.pixels().all(|&pixel| pixel == RED));
+    }
+
+    #[test]
+    fn fit_letterboxes_with_the_background() {
+        let scaled = scale_image(&solid(4, 2, RED), 4, 4, ScaleMode::Fit, &blue_background());
+        assert_eq!(scaled.dimensions(), (4, 4));
+        for x in 0..4 {
+            assert_eq!(*scaled.get_pixel(x, 0), BLUE);
+            assert_eq!(*scaled.get_pixel(x, 1), RED);
+            assert_eq!(*scaled.get_pixel(x, 2), RED);
+            assert_eq!(*scaled.get_pixel(x, 3), BLUE);
+        }
+    }
+
+    #[test]
+    fn center_keeps_the_original_size() {
+        let scaled = scale_image(&solid(2, 2, RED), 4, 4, ScaleMode::Center, &blue_background());
+        assert_eq!(*scaled.get_pixel(0, 0), BLUE);
+        assert_eq!(*scaled.get_pixel(1, 1), RED);
+        assert_eq!(*scaled.get_pixel(2, 2), RED);
+        assert_eq!(*scaled.get_pixel(3, 3), BLUE);
+    }
+
+    #[test]
+    fn tile_repeats_from_the_top_left() {
+        let mut tile = RgbaImage::from_pixel(2, 2, BLUE);
+        tile.put_pixel(0, 0, RED);
+        let scaled = scale_image(&DynamicImage::ImageRgba8(tile), 5, 5, ScaleMode::Tile, &Fill::Solid(Color::rgb(0, 0, 0)));
+        for (x, y) in [(0, 0), (2, 0), (4, 2), (2, 4), (4, 4)] {
+            assert_eq!(*scaled.get_pixel(x, y), RED, "pixel {x},{y}");
+        }
+        for (x, y) in [(1, 0), (3, 3), (4, 1)] {
+            assert_eq!(*scaled.get_pixel(x, y), BLUE, "pixel {x},{y}");
+        }
+    }
+
+    #[test]
+    fn stretch_ignores_the_aspect_ratio() {
+        let scaled = scale_image(&solid(2, 1, RED), 3, 6, ScaleMode::Stretch, &blue_background());
+        assert_eq!(scaled.dimensions(), (3, 6));
+        assert!(scaled.pixels().all(|&pixel| pixel == RED));
+    }
+
+    #[test]
+    fn transparent_pixels_show_the_background() {
+        let mut img = RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 0]));
+        img.put_pixel(1, 0, Rgba([255, 0, 0, 128]));
+        let composited = over_background(&Fill::Solid(Color::rgb(255, 255, 255)), img);
+        assert_eq!(*composited.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+        assert_eq!(*composited.get_pixel(1, 0), Rgba([255, 127, 127, 255]));
+    }
+
+    #[test]
+    fn opaque_images_are_not_composited() {
+        let img = RgbaImage::from_pixel(2, 2, RED);
+        assert_eq!(over_background(&blue_background(), img.clone()), img);
+    }
+
+    #[test]
+    fn empty_size_is_an_error() {
+        let result = render_image(Path::new("missing.png"), 0, 10, ScaleMode::Fill, &blue_background());
+        assert!(matches!(result, Err(PaberError::EmptySize)));
+    }
+}
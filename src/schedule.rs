@@ -0,0 +1,178 @@
+use std::{str::FromStr, time::{Duration, Instant}};
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Weekday};
+
+/// When a cycle switches to its next image or a generated wallpaper is generated again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// A fixed time after the previous switch
+    Every(Duration),
+    /// At a time of day, every day or on one day of the week
+    At { time: NaiveTime, weekday: Option<Weekday> },
+}
+
+impl Schedule {
+    /// The wall-clock time of the next switch of a time-of-day schedule.
+    pub fn next_time_of_day(&self) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Every(_) => None,
+            Schedule::At { .. } => Some(self.next_after(Local::now())),
+        }
+    }
+
+    /// The first time after `now` the schedule fires.
+    pub fn next_after(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let (time, weekday) = match *self {
+            Schedule::Every(interval) => return now + interval,
+            Schedule::At { time, weekday } => (time, weekday),
+        };
+        let mut date = now.date_naive();
+        loop {
+            // A time skipped by a DST change doesn't happen that day
+            if weekday.is_none_or(|weekday| date.weekday() == weekday)
+                && let Some(at) = Local.from_local_datetime(&date.and_time(time)).earliest()
+                && at > now
+            {
+                return at;
+            }
+            date = date.succ_opt().expect("date out of range");
+        }
+    }
+}
+
+/// The `Instant` a wall-clock time falls on, as far as the clocks agree right now.
+pub fn instant_at(at: DateTime<Local>) -> Instant {
+    Instant::now() + (at - Local::now()).to_std().unwrap_or_default()
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Parses seconds (`3600`), a duration with units (`3h`, `1h30m`, `every 90m`), or a time
+    /// of day: `every day at 07:00`, `daily at 07:00`, `at 07:00` or `every monday at 07:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        let rest = s.strip_prefix("every ").unwrap_or(&s).trim();
+
+        let at = match rest.strip_prefix("at ") {
+            Some(time) => Some(("day", time)),
+            None => rest.split_once(" at "),
+        };
+        let Some((day, time)) = at else {
+            return parse_duration(rest).map(Schedule::Every);
+        };
+        let weekday = match day.trim() {
+            "day" | "daily" => None,
+            day => Some(day.parse::<Weekday>().map_err(|_| format!("unknown day '{day}' (expected day or a weekday)"))?),
+        };
+        let time = time.trim();
+        let time = NaiveTime::parse_from_str(time, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+            .map_err(|_| format!("invalid time '{time}' (expected HH:MM)"))?;
+        Ok(Schedule::At { time, weekday })
+    }
+}
+
+/// Longer intervals are almost certainly typos, and far longer ones overflow `Instant`.
+const MAX_INTERVAL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Parses seconds or numbers with the units `s`, `m`, `h` and `d`, e.g. `1h30m`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid interval '{s}' (expected seconds, a duration like 3h or 1h30m, or e.g. \"every day at 07:00\")");
+    let seconds = match s.parse::<u64>() {
+        Ok(seconds) => seconds,
+        Err(_) => {
+            let mut seconds = 0u64;
+            let mut rest = s;
+            while !rest.is_empty() {
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+                let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+                let rest_after = rest[digits..].trim_start();
+                let unit_end = rest_after.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest_after.len());
+                let unit = match &rest_after[..unit_end] {
+                    "s" | "sec" | "secs" | "second" | "seconds" => 1,
+                    "m" | "min" | "mins" | "minute" | "minutes" => 60,
+                    "h" | "hour" | "hours" => 60 * 60,
+                    "d" | "day" | "days" => 24 * 60 * 60,
+                    _ => return Err(invalid()),
+                };
+                seconds = value.checked_mul(unit).and_then(|v| seconds.checked_add(v)).ok_or_else(invalid)?;
+                rest = rest_after[unit_end..].trim_start();
+            }
+            seconds
+        }
+    };
+    if seconds == 0 {
+        return Err("the interval has to be at least one second".to_string());
+    }
+    let interval = Duration::from_secs(seconds);
+    if interval > MAX_INTERVAL {
+        return Err(format!("invalid interval '{s}' (at most 365 days)"));
+    }
+    Ok(interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32, weekday: Option<Weekday>) -> Schedule {
+        Schedule::At { time: NaiveTime::from_hms_opt(hour, minute, 0).unwrap(), weekday }
+    }
+
+    fn local(date: &str, time: &str) -> DateTime<Local> {
+        let naive = chrono::NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%Y-%m-%d %H:%M:%S").unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap()
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!("3600".parse(), Ok(Schedule::Every(Duration::from_secs(3600))));
+        assert_eq!("90m".parse(), Ok(Schedule::Every(Duration::from_secs(90 * 60))));
+        assert_eq!("1h30m".parse(), Ok(Schedule::Every(Duration::from_secs(90 * 60))));
+        assert_eq!("every 2 hours".parse(), Ok(Schedule::Every(Duration::from_secs(2 * 60 * 60))));
+        assert_eq!("1d 12h".parse(), Ok(Schedule::Every(Duration::from_secs(36 * 60 * 60))));
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!("every day at 07:00".parse(), Ok(at(7, 0, None)));
+        assert_eq!("daily at 7:30".parse(), Ok(at(7, 30, None)));
+        assert_eq!("at 23:15".parse(), Ok(at(23, 15, None)));
+        assert_eq!("Every Monday at 07:00".parse(), Ok(at(7, 0, Some(Weekday::Mon))));
+        assert_eq!("every fri at 18:00".parse(), Ok(at(18, 0, Some(Weekday::Fri))));
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for input in ["", "0", "0s", "5x", "h", "every someday at 07:00", "every day at 25:00", "every day at noon"] {
+            assert!(input.parse::<Schedule>().is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_intervals_beyond_a_year() {
+        assert!("365d".parse::<Schedule>().is_ok());
+        assert!("366d".parse::<Schedule>().is_err());
+        assert!("18446744073709551615".parse::<Schedule>().is_err());
+        assert!("3000000000000d".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn next_time_of_day() {
+        // 2026-10-14 is a Wednesday
+        let now = local("2026-10-14", "08:00:00");
+        assert_eq!(at(7, 0, None).next_after(now), local("2026-10-15", "07:00:00"));
+        assert_eq!(at(9, 0, None).next_after(now), local("2026-10-14", "09:00:00"));
+        assert_eq!(at(8, 0, None).next_after(now), local("2026-10-15", "08:00:00"));
+        assert_eq!(at(7, 0, Some(Weekday::Mon)).next_after(now), local("2026-10-19", "07:00:00"));
+        assert_eq!(at(9, 0, Some(Weekday::Wed)).next_after(now), local("2026-10-14", "09:00:00"));
+        assert_eq!(at(7, 0, Some(Weekday::Wed)).next_after(now), local("2026-10-21", "07:00:00"));
+    }
+
+    #[test]
+    fn next_interval() {
+        let now = local("2026-10-14", "08:00:00");
+        assert_eq!(Schedule::Every(Duration::from_secs(90 * 60)).next_after(now), local("2026-10-14", "09:30:00"));
+    }
+}
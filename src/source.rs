@@ -0,0 +1,52 @@
+use std::{fmt, path::Path};
+
+use crate::color::Fill;
+
+/// What a single output shows.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Plain(Fill),
+    Image(String),
+    /// Generated image with an optional user prompt, enriched at generation time
+    Generated(Option<String>),
+    /// Directories to cycle through
+    Cycle(Vec<String>),
+}
+
+impl Source {
+    /// Parses the value side of `--output MONITOR=VALUE`.
+    ///
+    /// A file is an image, a directory is cycled, `generated` or `generated:PROMPT`
+    /// generates an image and anything else is parsed as a colour or gradient.
+    pub fn parse(value: &str) -> Result<Source, String> {
+        let value = value.trim();
+        if value == "generated" {
+            return Ok(Source::Generated(None));
+        }
+        if let Some(prompt) = value.strip_prefix("generated:") {
+            return Ok(Source::Generated(Some(prompt.to_string())));
+        }
+
+        let path = Path::new(value);
+        if path.is_dir() {
+            return Ok(Source::Cycle(vec![value.to_string()]));
+        }
+        if path.is_file() {
+            return Ok(Source::Image(value.to_string()));
+        }
+        Fill::parse(value).map(Source::Plain)
+            .map_err(|e| format!("'{value}' is not an image, a directory or a colour ({e})"))
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Plain(fill) => write!(f, "{fill}"),
+            Source::Image(path) => write!(f, "{path}"),
+            Source::Cycle(dirs) => write!(f, "{}", dirs.join(", ")),
+            Source::Generated(None) => write!(f, "generated"),
+            Source::Generated(Some(prompt)) => write!(f, "generated:{prompt}"),
+        }
+    }
+}
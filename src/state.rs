@@ -1,16 +1,50 @@
-use wayland_client::{Connection, Dispatch, QueueHandle, protocol::{wl_buffer, wl_compositor, wl_output, wl_registry, wl_shm, wl_shm_pool, wl_surface}};
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle, protocol::{wl_buffer, wl_callback, wl_compositor, wl_output, wl_region, wl_registry, wl_shm, wl_shm_pool, wl_surface}};
+use wayland_protocols::wp::{fractional_scale::v1::client::{wp_fractional_scale_manager_v1, wp_fractional_scale_v1}, single_pixel_buffer::v1::client::wp_single_pixel_buffer_manager_v1, viewporter::client::{wp_viewport, wp_viewporter}};
 use wayland_protocols_wlr::layer_shell::v1::client::{zwlr_layer_shell_v1, zwlr_layer_surface_v1};
 
+use chrono::{DateTime, Local};
+use image::RgbaImage;
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::{Duration, Instant}};
+
+use crate::{animation::{AnimationCache, AnimationSettings, Playback}, buffer::BufferPool, client::{buffer_released, frame_done}, color::{Color, Fill}, error::PaberError, generate::{GenerationKey, Generator}, monitor::MonitorNumbers, playlist::{Order, Playlist, ScanOptions}, render::ScaleMode, schedule::{Schedule, instant_at}, source::Source, timer::{boot_time, instant_at_boot}, transition::{Transition, TransitionSettings}};
+
+
+/// How often cycles switch images by default
+const DEFAULT_INTERVAL: Schedule = Schedule::Every(Duration::from_secs(60 * 60));
 
 pub struct AppState {
     pub compositor: Option<wl_compositor::WlCompositor>,
     pub layer_shell: Option<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
     pub shm: Option<wl_shm::WlShm>, // Shared memory
+    // Optional, used for fractional scaling
+    pub viewporter: Option<wp_viewporter::WpViewporter>,
+    pub fractional_scale_manager: Option<wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1>,
+    // Optional, used for solid colours together with the viewporter
+    pub single_pixel_buffer_manager: Option<wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1>,
 
     // Monitors
-    pub outputs: Vec<wl_output::WlOutput>,
+    pub outputs: Vec<Output>,
+    pub monitor_numbers: MonitorNumbers,
 
+    /// One per output. Positions shift when an output goes away, so they are only held
+    /// within an event, monitors are selected by their `Output::number`
     pub wallpapers: Vec<Wallpaper>,
+    /// Sources set with `paber ctl set` by monitor number, given back to a monitor whose
+    /// surface is recreated or that is plugged in again
+    pub runtime_sources: HashMap<usize, Source>,
+
+    /// How wallpapers replace each other
+    pub transition: TransitionSettings,
+    pub animation: AnimationSettings,
+    /// Animated images, decoded once and scaled once per output size
+    pub animations: AnimationCache,
+    /// Which files of cycled directories are shown
+    pub scan: ScanOptions,
+    /// PABER_HOME, where cycle positions are saved
+    pub home: Option<PathBuf>,
+    /// Generates images on a worker thread, set once the event loop is created
+    pub generator: Option<Generator>,
 }
 
 impl AppState {
@@ -19,30 +53,315 @@ impl AppState {
             compositor: None,
             layer_shell: None,
             shm: None,
+            viewporter: None,
+            fractional_scale_manager: None,
+            single_pixel_buffer_manager: None,
             outputs: Vec::new(),
+            monitor_numbers: MonitorNumbers::default(),
             wallpapers: Vec::new(),
+            runtime_sources: HashMap::new(),
+            transition: TransitionSettings::default(),
+            animation: AnimationSettings::default(),
+            animations: AnimationCache::default(),
+            scan: ScanOptions::default(),
+            home: None,
+            generator: None,
+        }
+    }
+}
+
+impl AppState {
+    pub fn output(&self, wl_output: &wl_output::WlOutput) -> Option<&Output> {
+        self.outputs.iter().find(|o| o.wl_output == *wl_output)
+    }
+
+    /// Marks an output as fully described and numbers it the first time.
+    fn describe_output(&mut self, wl_output: &wl_output::WlOutput) {
+        let Some(position) = self.outputs.iter().position(|o| o.wl_output == *wl_output) else {
+            return;
+        };
+        self.outputs[position].done = true;
+        if self.outputs[position].number.is_none() {
+            let name = self.outputs[position].name.clone();
+            let number = self.monitor_numbers.assign(name.as_deref(), |number| self.outputs.iter().any(|o| o.number == Some(number)));
+            self.outputs[position].number = Some(number);
         }
     }
+
+    /// Destroys the surfaces of every wallpaper matching `remove`.
+    fn remove_wallpapers(&mut self, remove: impl Fn(&Wallpaper) -> bool) {
+        self.wallpapers.retain(|wallpaper| {
+            if !remove(wallpaper) {
+                return true;
+            }
+            if let Some(fractional) = &wallpaper.fractional {
+                fractional.destroy();
+            }
+            if let Some(viewport) = &wallpaper.viewport {
+                viewport.destroy();
+            }
+            wallpaper.layer_surface.destroy();
+            wallpaper.surface.destroy();
+            false
+        });
+    }
+}
+
+pub struct Output {
+    pub wl_output: wl_output::WlOutput,
+    /// Registry name of the wl_output global, used to notice its removal
+    pub global_name: u32,
+    /// Connector name, e.g. "DP-1" (wl_output v4)
+    pub name: Option<String>,
+    /// Human readable description, usually make, model and serial (wl_output v4)
+    pub description: Option<String>,
+    /// Integer scale of the output, for compositors without preferred_buffer_scale
+    pub scale: i32,
+    /// All output properties have been received
+    pub done: bool,
+    /// What the monitor is selected by, given once it is described
+    pub number: Option<usize>,
 }
 
 pub struct Wallpaper {
+    pub output: wl_output::WlOutput,
     pub surface: wl_surface::WlSurface,
     pub layer_surface: zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+    pub viewport: Option<wp_viewport::WpViewport>,
+    pub fractional: Option<wp_fractional_scale_v1::WpFractionalScaleV1>,
+    /// Logical size from the configure event
     pub width: u32,
     pub height: u32,
     pub configured: bool,
+    /// Integer buffer scale from preferred_buffer_scale or the output
+    pub scale_factor: i32,
+    /// Scale in 120ths from wp_fractional_scale_v1, e.g. 180 for 1.5
+    pub fractional_scale: Option<u32>,
+    pub scale_mode: ScaleMode,
+    /// Painted behind images that don't cover the whole output
+    pub background: Fill,
+
+    pub source: Option<Source>,
+    /// How often a cycle source switches images or a generated source is generated again.
+    /// Cycles switch every hour unless told otherwise
+    pub interval: Option<Schedule>,
+    pub order: Order,
+    pub playlist: Option<Playlist>,
+    /// Image generated for a generated source, shown until the next one is ready
+    pub generated: Option<String>,
+    /// Waiting for the generator to finish an image for this wallpaper
+    pub generating: bool,
+    pub next_switch: Option<Instant>,
+    /// Wall-clock time of `next_switch` for a time-of-day schedule. Instants stand still
+    /// during suspend, so `next_switch` is moved back in line with it by `sync_switch`
+    pub switch_at: Option<DateTime<Local>>,
+    /// Boot-clock time of `next_switch` for an interval. Unlike instants, the boot clock
+    /// counts time spent suspended, so an interval that ran out during a suspend is due on resume
+    pub switch_due: Option<Duration>,
+    pub paused: bool,
+
+    /// What is on screen, kept as the starting point of the next transition
+    pub shown: Option<Arc<RgbaImage>>,
+    /// The wallpaper is a solid colour, shown with a single-pixel buffer when possible
+    pub solid: Option<Color>,
+    pub transition: Option<Transition>,
+    pub animation: Option<Playback>,
+    pub buffers: BufferPool,
+    /// A frame couldn't be drawn because every buffer was busy
+    pub stalled: bool,
+    /// Why the last wallpaper couldn't be shown, reported by `paber ctl status`
+    pub error: Option<String>,
+
+    /// The source and settings for this output still have to be looked up
+    pub needs_setup: bool,
+    /// The output changed size and the current wallpaper has to be drawn again
+    pub needs_redraw: bool,
+}
+
+impl Wallpaper {
+    pub fn new(output: wl_output::WlOutput, surface: wl_surface::WlSurface, layer_surface: zwlr_layer_surface_v1::ZwlrLayerSurfaceV1, scale_factor: i32) -> Self {
+        Wallpaper {
+            output,
+            surface,
+            layer_surface,
+            viewport: None,
+            fractional: None,
+            width: 0, // Will be updated by configure event
+            height: 0,
+            configured: false,
+            scale_factor,
+            fractional_scale: None,
+            scale_mode: ScaleMode::default(),
+            background: Fill::Solid(Color::rgb(0, 0, 0)),
+            source: None,
+            interval: None,
+            order: Order::default(),
+            playlist: None,
+            generated: None,
+            generating: false,
+            next_switch: None,
+            switch_at: None,
+            switch_due: None,
+            paused: false,
+            shown: None,
+            solid: None,
+            transition: None,
+            animation: None,
+            buffers: BufferPool::default(),
+            stalled: false,
+            error: None,
+            needs_setup: true,
+            needs_redraw: false,
+        }
+    }
+
+    /// The images a generated source can show, matching the prompt and the shape of the output.
+    pub fn generation_key(&self) -> Option<GenerationKey> {
+        match &self.source {
+            Some(Source::Generated(user_prompt)) => Some(GenerationKey::new(user_prompt.clone(), self.buffer_size())),
+            _ => None,
+        }
+    }
+
+    /// Size of the buffer in physical pixels.
+    pub fn buffer_size(&self) -> (u32, u32) {
+        match (self.fractional_scale, &self.viewport) {
+            // Rounded half away from zero, as the fractional scale protocol asks
+            (Some(scale), Some(_)) => ((self.width * scale + 60) / 120, (self.height * scale + 60) / 120),
+            _ if self.surface.version() >= 3 || self.viewport.is_some() => {
+                (self.width * self.scale_factor as u32, self.height * self.scale_factor as u32)
+            }
+            _ => (self.width, self.height),
+        }
+    }
+
+    /// Effective scale of the buffer relative to the logical size.
+    pub fn scale(&self) -> f64 {
+        self.buffer_size().0 as f64 / self.width.max(1) as f64
+    }
+
+    /// Tells the compositor how the next buffer maps to the logical surface size.
+    pub fn set_buffer_scale(&self) {
+        match &self.viewport {
+            Some(viewport) => {
+                self.surface.set_buffer_scale(1);
+                viewport.set_destination(self.width as i32, self.height as i32);
+            }
+            // set_buffer_scale needs wl_surface version 3
+            None if self.surface.version() >= 3 => self.surface.set_buffer_scale(self.scale_factor),
+            None => {}
+        }
+    }
+
+    /// Schedules the next cycle switch or generation from now.
+    pub fn schedule_switch(&mut self) {
+        let schedule = match (&self.source, self.interval) {
+            (Some(Source::Cycle(_)), interval) => Some(interval.unwrap_or(DEFAULT_INTERVAL)),
+            (Some(Source::Generated(_)), Some(interval)) => Some(interval),
+            _ => None,
+        };
+        self.switch_at = schedule.and_then(|schedule| schedule.next_time_of_day());
+        self.switch_due = match schedule {
+            Some(Schedule::Every(interval)) => Some(boot_time() + interval),
+            _ => None,
+        };
+        self.next_switch = self.switch_at.map(instant_at).or(self.switch_due.map(instant_at_boot));
+    }
+
+    /// Switches on the next pass of the event loop.
+    pub fn switch_now(&mut self) {
+        self.next_switch = Some(Instant::now());
+        self.switch_at = None;
+        self.switch_due = None;
+    }
+
+    /// Moves a time-of-day switch to where the wall clock puts it, after a suspend or a
+    /// change of the system time, and an interval to where the boot clock puts it.
+    pub fn sync_switch(&mut self) {
+        if self.next_switch.is_some() {
+            if let Some(at) = self.switch_at {
+                self.next_switch = Some(instant_at(at));
+            } else if let Some(due) = self.switch_due {
+                self.next_switch = Some(instant_at_boot(due));
+            }
+        }
+    }
+
+    /// Logs an error and keeps it for `paber ctl status`.
+    pub fn report(&mut self, error: &PaberError) {
+        eprintln!("Error: {error}");
+        self.error = Some(error.to_string());
+    }
+
+    /// Forgets everything set by the command line or config, keeping the surface.
+    /// The settings are looked up again on the next `update_outputs`.
+    pub fn reset_settings(&mut self) {
+        self.needs_setup = true;
+        self.scale_mode = ScaleMode::default();
+        self.background = Fill::Solid(Color::rgb(0, 0, 0));
+        self.source = None;
+        self.interval = None;
+        self.order = Order::default();
+        self.playlist = None;
+        self.generated = None;
+        self.generating = false;
+        self.next_switch = None;
+        self.switch_at = None;
+        self.switch_due = None;
+        self.paused = false;
+    }
 }
 
 impl Dispatch<wl_buffer::WlBuffer, ()> for AppState {
     fn event(
-            _state: &mut Self,
-            _proxy: &wl_buffer::WlBuffer,
-            _event: <wl_buffer::WlBuffer as wayland_client::Proxy>::Event,
+            state: &mut Self,
+            proxy: &wl_buffer::WlBuffer,
+            event: <wl_buffer::WlBuffer as wayland_client::Proxy>::Event,
             _data: &(),
             _conn: &Connection,
+            qhandle: &QueueHandle<Self>,
+        ) {
+        if let wl_buffer::Event::Release = event
+            && let Some(index) = state.wallpapers.iter_mut().position(|w| w.buffers.release(proxy))
+        {
+            buffer_released(state, qhandle, index);
+        }
+    }
+}
+
+/// User data of single-pixel buffers, which are destroyed as soon as the compositor releases them.
+pub struct SinglePixelBuffer;
+
+impl Dispatch<wl_buffer::WlBuffer, SinglePixelBuffer> for AppState {
+    fn event(
+            _state: &mut Self,
+            proxy: &wl_buffer::WlBuffer,
+            event: <wl_buffer::WlBuffer as wayland_client::Proxy>::Event,
+            _data: &SinglePixelBuffer,
+            _conn: &Connection,
             _qhandle: &QueueHandle<Self>,
         ) {
-        
+        if let wl_buffer::Event::Release = event {
+            proxy.destroy();
+        }
+    }
+}
+
+impl Dispatch<wl_callback::WlCallback, wl_surface::WlSurface> for AppState {
+    fn event(
+            state: &mut Self,
+            _proxy: &wl_callback::WlCallback,
+            event: <wl_callback::WlCallback as wayland_client::Proxy>::Event,
+            surface: &wl_surface::WlSurface,
+            _conn: &Connection,
+            qhandle: &QueueHandle<Self>,
+        ) {
+        // Frame callbacks pace transitions and animations
+        if let wl_callback::Event::Done { .. } = event
+            && let Some(index) = state.wallpapers.iter().position(|w| w.surface == *surface)
+        {
+            frame_done(state, qhandle, index);
+        }
     }
 }
 
@@ -60,6 +379,19 @@ impl Dispatch<wl_compositor::WlCompositor, ()> for AppState {
     }
 }
 
+impl Dispatch<wl_region::WlRegion, ()> for AppState {
+    fn event(
+            _state: &mut Self,
+            _proxy: &wl_region::WlRegion,
+            _event: <wl_region::WlRegion as wayland_client::Proxy>::Event,
+            _data: &(),
+            _conn: &Connection,
+            _qhandle: &QueueHandle<Self>,
+        ) {
+        
+    }
+}
+
 impl Dispatch<zwlr_layer_shell_v1::ZwlrLayerShellV1, ()> for AppState {
     fn event(
             _state: &mut Self,
@@ -74,17 +406,95 @@ impl Dispatch<zwlr_layer_shell_v1::ZwlrLayerShellV1, ()> for AppState {
 }
 
 impl Dispatch<wl_surface::WlSurface, ()> for AppState {
+    fn event(
+            state: &mut Self,
+            proxy: &wl_surface::WlSurface,
+            event: <wl_surface::WlSurface as wayland_client::Proxy>::Event,
+            _data: &(),
+            _conn: &Connection,
+            _qhandle: &QueueHandle<Self>,
+        ) {
+        if let wl_surface::Event::PreferredBufferScale { factor } = event
+            && let Some(wallpaper) = state.wallpapers.iter_mut().find(|w| w.surface == *proxy)
+            && wallpaper.scale_factor != factor
+        {
+            wallpaper.scale_factor = factor;
+            wallpaper.needs_redraw = wallpaper.configured;
+        }
+    }
+    
+}
+
+impl Dispatch<wp_fractional_scale_v1::WpFractionalScaleV1, ()> for AppState {
+    fn event(
+            state: &mut Self,
+            proxy: &wp_fractional_scale_v1::WpFractionalScaleV1,
+            event: <wp_fractional_scale_v1::WpFractionalScaleV1 as wayland_client::Proxy>::Event,
+            _data: &(),
+            _conn: &Connection,
+            _qhandle: &QueueHandle<Self>,
+        ) {
+        if let wp_fractional_scale_v1::Event::PreferredScale { scale } = event
+            && let Some(wallpaper) = state.wallpapers.iter_mut().find(|w| w.fractional.as_ref() == Some(proxy))
+            && wallpaper.fractional_scale != Some(scale)
+        {
+            println!("Fractional scale: {}", scale as f64 / 120.0);
+            wallpaper.fractional_scale = Some(scale);
+            wallpaper.needs_redraw = wallpaper.configured;
+        }
+    }
+}
+
+impl Dispatch<wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1, ()> for AppState {
     fn event(
             _state: &mut Self,
-            _proxy: &wl_surface::WlSurface,
-            _event: <wl_surface::WlSurface as wayland_client::Proxy>::Event,
+            _proxy: &wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1,
+            _event: <wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1 as wayland_client::Proxy>::Event,
+            _data: &(),
+            _conn: &Connection,
+            _qhandle: &QueueHandle<Self>,
+        ) {
+        
+    }
+}
+
+impl Dispatch<wp_viewporter::WpViewporter, ()> for AppState {
+    fn event(
+            _state: &mut Self,
+            _proxy: &wp_viewporter::WpViewporter,
+            _event: <wp_viewporter::WpViewporter as wayland_client::Proxy>::Event,
+            _data: &(),
+            _conn: &Connection,
+            _qhandle: &QueueHandle<Self>,
+        ) {
+        
+    }
+}
+
+impl Dispatch<wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1, ()> for AppState {
+    fn event(
+            _state: &mut Self,
+            _proxy: &wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1,
+            _event: <wp_single_pixel_buffer_manager_v1::WpSinglePixelBufferManagerV1 as wayland_client::Proxy>::Event,
+            _data: &(),
+            _conn: &Connection,
+            _qhandle: &QueueHandle<Self>,
+        ) {
+        
+    }
+}
+
+impl Dispatch<wp_viewport::WpViewport, ()> for AppState {
+    fn event(
+            _state: &mut Self,
+            _proxy: &wp_viewport::WpViewport,
+            _event: <wp_viewport::WpViewport as wayland_client::Proxy>::Event,
             _data: &(),
             _conn: &Connection,
             _qhandle: &QueueHandle<Self>,
         ) {
         
     }
-    
 }
 
 impl Dispatch<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1, ()> for AppState {
@@ -101,12 +511,23 @@ impl Dispatch<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1, ()> for AppState {
 
                 // Find which wallpaper this event belongs to and update it
                 if let Some(wallpaper) = state.wallpapers.iter_mut().find(|w| w.layer_surface == *proxy) {
+                    if wallpaper.configured && (wallpaper.width, wallpaper.height) != (width, height) {
+                        wallpaper.needs_redraw = true;
+                    }
                     wallpaper.width = width;
                     wallpaper.height = height;
                     wallpaper.configured = true;
                     println!("Monitor configured: {}x{}", width, height);
                 }
             }
+
+            if let zwlr_layer_surface_v1::Event::Closed = event {
+                // The compositor won't show this surface again. If the output is still
+                // around, a new surface is created for it by `create_missing_surfaces`, and
+                // `update_outputs` gives it back a source set with `paber ctl set`.
+                println!("Layer surface closed by the compositor");
+                state.remove_wallpapers(|w| w.layer_surface == *proxy);
+            }
         }
 }
 
@@ -137,7 +558,36 @@ impl Dispatch<wl_shm_pool::WlShmPool, ()> for AppState {
 }
 
 impl Dispatch<wl_output::WlOutput, ()> for AppState {
-    fn event(_: &mut Self, _: &wl_output::WlOutput, _: wl_output::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {
+    fn event(
+            state: &mut Self,
+            proxy: &wl_output::WlOutput,
+            event: <wl_output::WlOutput as wayland_client::Proxy>::Event,
+            _data: &(),
+            _conn: &Connection,
+            _qhandle: &QueueHandle<Self>,
+        ) {
+        if let wl_output::Event::Done = event {
+            state.describe_output(proxy);
+            return;
+        }
+        let Some(output) = state.outputs.iter_mut().find(|o| o.wl_output == *proxy) else {
+            return;
+        };
+        match event {
+            wl_output::Event::Name { name } => output.name = Some(name),
+            wl_output::Event::Description { description } => output.description = Some(description),
+            wl_output::Event::Scale { factor } => {
+                output.scale = factor;
+                // Surfaces that get preferred_buffer_scale (wl_surface v6) ignore the output scale
+                for wallpaper in state.wallpapers.iter_mut().filter(|w| w.output == *proxy && w.surface.version() < 6) {
+                    if wallpaper.scale_factor != factor {
+                        wallpaper.scale_factor = factor;
+                        wallpaper.needs_redraw = wallpaper.configured;
+                    }
+                }
+            }
+            _ => {},
+        }
     }
 }
 
@@ -150,12 +600,12 @@ impl Dispatch<wl_registry::WlRegistry, ()> for AppState {
             _conn: &Connection,
             qhandle: &QueueHandle<Self>,
         ) {
-        if let wl_registry::Event::Global { name, interface, version: _ } = event {
+        if let wl_registry::Event::Global { name, ref interface, version } = event {
             match interface.as_str() {
                 "wl_compositor" => {
                     let compositor = proxy.bind::<wl_compositor::WlCompositor, _, _>(
                         name,
-                        4, // (version 4 is standard/safe)
+                        version.min(6), // (version 6 adds preferred_buffer_scale)
                         qhandle,
                         ()
                     );
@@ -173,14 +623,36 @@ impl Dispatch<wl_registry::WlRegistry, ()> for AppState {
                     state.layer_shell = Some(layer_shell);
                 },
                 "wl_shm" => { state.shm = Some(proxy.bind(name, 1, qhandle, ())); },
+                "wp_viewporter" => { state.viewporter = Some(proxy.bind(name, 1, qhandle, ())); },
+                "wp_fractional_scale_manager_v1" => {
+                    state.fractional_scale_manager = Some(proxy.bind(name, 1, qhandle, ()));
+                },
+                "wp_single_pixel_buffer_manager_v1" => {
+                    state.single_pixel_buffer_manager = Some(proxy.bind(name, 1, qhandle, ()));
+                },
                 "wl_output" => {
-                    let output = proxy.bind::<wl_output::WlOutput, _, _>(name, 4, qhandle, ());
-                    state.outputs.push(output);
+                    // Name and Description need version 4
+                    let wl_output = proxy.bind::<wl_output::WlOutput, _, _>(name, version.min(4), qhandle, ());
+                    state.outputs.push(Output { wl_output: wl_output.clone(), global_name: name, name: None, description: None, scale: 1, done: false, number: None });
+                    // Before version 2 there is no Done event to wait for
+                    if version < 2 {
+                        state.describe_output(&wl_output);
+                    }
                 },
                 _ => {},
             }
         }
-        
+
+        if let wl_registry::Event::GlobalRemove { name } = event
+            && let Some(position) = state.outputs.iter().position(|o| o.global_name == name)
+        {
+            let output = state.outputs.remove(position);
+            println!("Output {} was removed", output.name.as_deref().unwrap_or("unknown"));
+            state.remove_wallpapers(|w| w.output == output.wl_output);
+            if output.wl_output.version() >= 3 {
+                output.wl_output.release();
+            }
+        }
     }
     
 }
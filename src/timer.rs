@@ -0,0 +1,81 @@
+use std::{os::fd::{AsFd, BorrowedFd}, time::{Duration, Instant}};
+
+use nix::{sys::{time::{TimeSpec, TimeValLike}, timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags}}, time::{self, ClockId as Clock}};
+
+/// A timerfd that becomes readable at the earliest pending deadline of the
+/// event loop, e.g. the next cycle switch or animation frame. It runs on the boot
+/// clock, which keeps counting during suspend, so a deadline that fell into a suspend
+/// wakes the loop right after resuming. `Instant`s stand still during suspend, so
+/// deadlines that should count it are kept on the wall clock (times of day) or the boot
+/// clock (intervals, see `boot_time`) and moved back in line once the loop wakes up.
+pub struct DeadlineTimer {
+    fd: TimerFd,
+}
+
+impl DeadlineTimer {
+    pub fn new() -> nix::Result<Self> {
+        let fd = TimerFd::new(ClockId::CLOCK_BOOTTIME, TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC)?;
+        Ok(DeadlineTimer { fd })
+    }
+
+    /// Arms the timer for `deadline`, or disarms it when there is nothing to wait for.
+    pub fn set(&self, deadline: Option<Instant>) {
+        let result = match deadline {
+            Some(deadline) => {
+                // A zero timeout would disarm the timer, so deadlines that passed fire right away
+                let timeout = deadline.saturating_duration_since(Instant::now()).max(Duration::from_nanos(1));
+                self.fd.set(Expiration::OneShot(TimeSpec::from_duration(timeout)), TimerSetTimeFlags::empty())
+            }
+            None => self.fd.unset(),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to set the timer: {e}");
+        }
+    }
+
+    /// Acknowledges an expiration so the fd stops being readable.
+    pub fn clear(&self) {
+        // The fd is non-blocking, there is nothing to read if it didn't expire
+        let _ = self.fd.wait();
+    }
+}
+
+/// Time since boot, including time spent suspended.
+pub fn boot_time() -> Duration {
+    // The boot clock exists on every kernel paber runs on
+    let now = time::clock_gettime(Clock::CLOCK_BOOTTIME).expect("CLOCK_BOOTTIME is available");
+    Duration::from_nanos(now.num_nanoseconds() as u64)
+}
+
+/// The `Instant` a boot-clock time falls on, as far as the clocks agree right now.
+pub fn instant_at_boot(at: Duration) -> Instant {
+    Instant::now() + at.saturating_sub(boot_time())
+}
+
+impl AsFd for DeadlineTimer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_boot_clock_moves_forward() {
+        let before = boot_time();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(boot_time() >= before + Duration::from_millis(10));
+    }
+
+    #[test]
+    fn converts_boot_clock_times_to_instants() {
+        let now = Instant::now();
+        let later = instant_at_boot(boot_time() + Duration::from_secs(60));
+        assert!(later >= now + Duration::from_secs(59) && later <= Instant::now() + Duration::from_secs(60));
+        // Times that passed, e.g. during a suspend, are due right away
+        let passed = instant_at_boot(boot_time().saturating_sub(Duration::from_secs(60)));
+        assert!(passed <= Instant::now());
+    }
+}
@@ -0,0 +1,303 @@
+use std::{num::NonZeroUsize, str::FromStr, sync::Arc, thread, time::{Duration, Instant}};
+
+use image::RgbaImage;
+
+use crate::{color::{Color, parse_direction, parse_position}, render::is_opaque};
+
+/// How a new wallpaper replaces the one on screen.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TransitionKind {
+    /// Swap the wallpaper in a single commit
+    #[default]
+    None,
+    Crossfade,
+    /// An edge moving across the output. CSS angle: 90 moves to the right
+    Wipe { angle: f32 },
+    /// A circle growing from a point relative to the output (0.0 to 1.0)
+    Grow { center: (f32, f32) },
+    /// The new wallpaper pushes the old one out, moving towards `angle`
+    Slide { angle: f32 },
+}
+
+impl FromStr for TransitionKind {
+    type Err = String;
+
+    /// Parses `none`, `crossfade`, `wipe [ANGLE]`, `slide [ANGLE]` or `grow [at POSITION]`.
+    /// Angles use the CSS gradient syntax (`45deg`, `to left`), positions too (`at 20% 80%`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        let (name, rest) = s.split_once(char::is_whitespace).unwrap_or((&s, ""));
+        let rest = rest.trim();
+        let angle = || match rest {
+            "" => Ok(90.0),
+            _ => parse_direction(rest).ok_or_else(|| format!("invalid angle '{rest}'")),
+        };
+        match name {
+            "none" if rest.is_empty() => Ok(TransitionKind::None),
+            "crossfade" if rest.is_empty() => Ok(TransitionKind::Crossfade),
+            "wipe" => Ok(TransitionKind::Wipe { angle: angle()? }),
+            "slide" => Ok(TransitionKind::Slide { angle: angle()? }),
+            "grow" => {
+                let center = match rest.strip_prefix("at") {
+                    Some(at) => parse_position(at)?,
+                    None if rest.is_empty() => (0.5, 0.5),
+                    None => return Err(format!("expected 'grow at POSITION', got '{s}'")),
+                };
+                Ok(TransitionKind::Grow { center })
+            }
+            _ => Err(format!("unknown transition '{s}' (expected none, crossfade, wipe, grow or slide)")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    #[default]
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps linear progress to eased progress, both from 0.0 to 1.0.
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+            Easing::EaseInOut if t < 0.5 => 4.0 * t * t * t,
+            Easing::EaseInOut => 1.0 - (2.0 - 2.0 * t).powi(3) / 2.0,
+        }
+    }
+}
+
+impl FromStr for Easing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "linear" => Ok(Easing::Linear),
+            "ease-in" => Ok(Easing::EaseIn),
+            "ease-out" => Ok(Easing::EaseOut),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            other => Err(format!("unknown easing '{other}' (expected linear, ease-in, ease-out or ease-in-out)")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransitionSettings {
+    pub kind: TransitionKind,
+    pub duration: Duration,
+    pub easing: Easing,
+}
+
+impl Default for TransitionSettings {
+    fn default() -> Self {
+        TransitionSettings { kind: TransitionKind::None, duration: Duration::from_secs(1), easing: Easing::default() }
+    }
+}
+
+/// Parses a duration in seconds, e.g. `1.5`.
+pub fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.trim().parse().map_err(|_| format!("invalid duration '{}'", s.trim()))?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("invalid duration '{}'", s.trim()))
+}
+
+/// A transition in progress between two images of the same size.
+pub struct Transition {
+    pub from: Arc<RgbaImage>,
+    pub to: Arc<RgbaImage>,
+    start: Instant,
+    settings: TransitionSettings,
+    /// Every frame is opaque when both images are
+    pub opaque: bool,
+}
+
+/// Width of the soft edge of wipe and grow, relative to the distance the edge travels
+const FEATHER: f32 = 0.02;
+
+impl Transition {
+    pub fn new(from: Arc<RgbaImage>, to: Arc<RgbaImage>, settings: TransitionSettings) -> Self {
+        let opaque = is_opaque(&from) && is_opaque(&to);
+        Transition { from, to, start: Instant::now(), settings, opaque }
+    }
+
+    /// Eased progress at `now`, 1.0 once the transition is over.
+    pub fn progress(&self, now: Instant) -> f32 {
+        let duration = self.settings.duration.as_secs_f32();
+        if duration <= 0.0 {
+            return 1.0;
+        }
+        let t = (now.duration_since(self.start).as_secs_f32() / duration).min(1.0);
+        self.settings.easing.apply(t)
+    }
+
+    /// Paints the frame at progress `t` into an ARGB8888 canvas the size of the images.
+    pub fn paint(&self, t: f32, canvas: &mut [u32]) {
+        let width = self.to.width() as usize;
+        if width == 0 {
+            return;
+        }
+        // Large outputs take longer than a frame to paint on one core, so the rows are split between threads
+        let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let rows = (canvas.len() / width).div_ceil(threads).max(1);
+        thread::scope(|scope| {
+            for (chunk, pixels) in canvas.chunks_mut(rows * width).enumerate() {
+                scope.spawn(move || {
+                    let first = chunk * rows * width;
+                    for (i, pixel) in pixels.iter_mut().enumerate() {
+                        let i = first + i;
+                        *pixel = self.pixel((i % width) as u32, (i / width) as u32, t);
+                    }
+                });
+            }
+        });
+    }
+
+    /// The ARGB8888 pixel at `x`, `y` at progress `t`.
+    fn pixel(&self, x: u32, y: u32, t: f32) -> u32 {
+        let (width, height) = self.to.dimensions();
+        let (w, h) = (width as f32, height as f32);
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let from = self.from.get_pixel(x, y).0;
+        let to = self.to.get_pixel(x, y).0;
+
+        let rgba = match self.settings.kind {
+            TransitionKind::None => to,
+            TransitionKind::Crossfade => mix(from, to, t),
+            TransitionKind::Wipe { angle } => {
+                let (dx, dy) = direction(angle);
+                let length = (w * dx).abs() + (h * dy).abs();
+                let position = ((px - w / 2.0) * dx + (py - h / 2.0) * dy) / length + 0.5;
+                mix(from, to, edge(position, t))
+            }
+            TransitionKind::Grow { center } => {
+                let (cx, cy) = (center.0 * w, center.1 * h);
+                let (far_x, far_y) = (cx.max(w - cx), cy.max(h - cy));
+                let radius = (far_x * far_x + far_y * far_y).sqrt();
+                let distance = ((px - cx).powi(2) + (py - cy).powi(2)).sqrt() / radius;
+                mix(from, to, edge(distance, t))
+            }
+            TransitionKind::Slide { angle } => {
+                let (dx, dy) = direction(angle);
+                // Distance after which the old image has left the output completely
+                let length = match (dx.abs() > 1e-6, dy.abs() > 1e-6) {
+                    (true, true) => (w / dx.abs()).min(h / dy.abs()),
+                    (true, false) => w / dx.abs(),
+                    _ => h / dy.abs(),
+                };
+                let entering = (px + dx * length * (1.0 - t), py + dy * length * (1.0 - t));
+                let leaving = (px - dx * length * t, py - dy * length * t);
+                sample(&self.to, entering)
+                    .or_else(|| sample(&self.from, leaving))
+                    .unwrap_or([0, 0, 0, 255])
+            }
+        };
+        let [r, g, b, a] = rgba;
+        Color { r, g, b, a }.to_argb8888()
+    }
+}
+
+/// Unit vector for a CSS angle: 0 points up, 90 points right.
+fn direction(angle: f32) -> (f32, f32) {
+    let rad = angle.to_radians();
+    (rad.sin(), -rad.cos())
+}
+
+/// How much of the new image shows at `position` (0.0 to 1.0 along the edge's path)
+/// when the edge has travelled `t` of the way.
+fn edge(position: f32, t: f32) -> f32 {
+    ((t * (1.0 + FEATHER) - position) / FEATHER).clamp(0.0, 1.0)
+}
+
+fn mix(from: [u8; 4], to: [u8; 4], t: f32) -> [u8; 4] {
+    std::array::from_fn(|i| (from[i] as f32 + (to[i] as f32 - from[i] as f32) * t).round() as u8)
+}
+
+fn sample(image: &RgbaImage, (x, y): (f32, f32)) -> Option<[u8; 4]> {
+    let (x, y) = (x.floor(), y.floor());
+    if x < 0.0 || y < 0.0 || x >= image.width() as f32 || y >= image.height() as f32 {
+        return None;
+    }
+    Some(image.get_pixel(x as u32, y as u32).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn parses_transition_kinds() {
+        let cases = [
+            ("none", TransitionKind::None),
+            (" Crossfade ", TransitionKind::Crossfade),
+            ("wipe", TransitionKind::Wipe { angle: 90.0 }),
+            ("wipe 45deg", TransitionKind::Wipe { angle: 45.0 }),
+            ("wipe to top left", TransitionKind::Wipe { angle: 315.0 }),
+            ("slide", TransitionKind::Slide { angle: 90.0 }),
+            ("slide 0.5turn", TransitionKind::Slide { angle: 180.0 }),
+            ("grow", TransitionKind::Grow { center: (0.5, 0.5) }),
+            ("grow at 20% 80%", TransitionKind::Grow { center: (0.2, 0.8) }),
+            ("GROW AT LEFT TOP", TransitionKind::Grow { center: (0.0, 0.0) }),
+        ];
+        for (s, kind) in cases {
+            assert_eq!(s.parse(), Ok(kind), "{s}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_transitions() {
+        for s in ["", "fade", "crossfade 45deg", "none at top", "wipe sideways", "slide to nowhere", "grow 20%", "grow at nowhere"] {
+            assert!(s.parse::<TransitionKind>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn parses_easings() {
+        let cases = [("linear", Easing::Linear), ("ease-in", Easing::EaseIn), (" EASE-OUT", Easing::EaseOut), ("ease-in-out", Easing::EaseInOut)];
+        for (s, easing) in cases {
+            assert_eq!(s.parse(), Ok(easing), "{s}");
+        }
+        assert!("ease".parse::<Easing>().is_err());
+    }
+
+    #[test]
+    fn easings_start_and_end_in_place() {
+        for easing in [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut] {
+            assert_eq!(easing.apply(0.0), 0.0);
+            assert_eq!(easing.apply(1.0), 1.0);
+        }
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+        assert!(Easing::EaseIn.apply(0.25) < 0.25 && Easing::EaseOut.apply(0.25) > 0.25);
+    }
+
+    #[test]
+    fn paints_every_row() {
+        // Tall enough for every thread to get rows, with a remainder
+        let (width, height) = (5, 37);
+        let from = Arc::new(RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 255])));
+        let to = Arc::new(RgbaImage::from_pixel(width, height, Rgba([0, 0, 255, 255])));
+        let settings = TransitionSettings { kind: TransitionKind::Wipe { angle: 180.0 }, ..Default::default() };
+        let transition = Transition::new(from, to, settings);
+
+        let mut canvas = vec![0; (width * height) as usize];
+        transition.paint(0.5, &mut canvas);
+        for (i, pixel) in canvas.iter().enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            assert_eq!(*pixel, transition.pixel(x, y, 0.5), "{x}, {y}");
+        }
+        // Wiping downwards, the top shows the new image and the bottom the old one
+        assert_eq!(canvas[0], 0xff0000ff);
+        assert_eq!(canvas[canvas.len() - 1], 0xffff0000);
+    }
+
+    #[test]
+    fn paints_nothing_for_an_empty_image() {
+        let empty = Arc::new(RgbaImage::new(0, 0));
+        let transition = Transition::new(empty.clone(), empty, TransitionSettings::default());
+        transition.paint(0.5, &mut []);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{cell::RefCell, collections::{HashMap, HashSet}, os::fd::{AsFd, BorrowedFd}, path::{Path, PathBuf}};
+
+use nix::{errno::Errno, sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor}};
+
+/// Changes to a directory that can add or remove images
+const EVENTS: AddWatchFlags = AddWatchFlags::IN_CREATE
+    .union(AddWatchFlags::IN_DELETE)
+    .union(AddWatchFlags::IN_CLOSE_WRITE)
+    .union(AddWatchFlags::IN_MOVED_FROM)
+    .union(AddWatchFlags::IN_MOVED_TO)
+    .union(AddWatchFlags::IN_DELETE_SELF)
+    .union(AddWatchFlags::IN_MOVE_SELF)
+    .union(AddWatchFlags::IN_ONLYDIR);
+
+/// Watches the directories of cycle sources with inotify. Readable when one of them changed.
+pub struct DirWatcher {
+    inotify: Inotify,
+    /// Directories being watched. Two paths share a descriptor when a symlink leads to the same directory
+    watches: RefCell<HashMap<PathBuf, WatchDescriptor>>,
+    /// The directories asked for by the last `watch`, including ones that couldn't be watched
+    wanted: RefCell<HashSet<PathBuf>>,
+}
+
+impl DirWatcher {
+    pub fn new() -> nix::Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        Ok(DirWatcher { inotify, watches: RefCell::default(), wanted: RefCell::default() })
+    }
+
+    /// Watches exactly `dirs`, adding and removing watches as needed.
+    pub fn watch<'a>(&self, dirs: impl IntoIterator<Item = &'a Path>) {
+        let dirs: HashSet<PathBuf> = dirs.into_iter().map(Path::to_path_buf).collect();
+        if *self.wanted.borrow() == dirs {
+            return;
+        }
+
+        let mut watches = self.watches.borrow_mut();
+        watches.retain(|dir, wd| {
+            if dirs.contains(dir) {
+                return true;
+            }
+            // The kernel already dropped the watch if the directory is gone
+            let _ = self.inotify.rm_watch(*wd);
+            false
+        });
+        for dir in &dirs {
+            if watches.contains_key(dir) {
+                continue;
+            }
+            match self.inotify.add_watch(dir.as_path(), EVENTS) {
+                Ok(wd) => {
+                    watches.insert(dir.clone(), wd);
+                }
+                Err(e) => eprintln!("Failed to watch {}: {e}", dir.display()),
+            }
+        }
+        *self.wanted.borrow_mut() = dirs;
+    }
+
+    /// Reads the pending events and returns the directories that changed.
+    pub fn changed_dirs(&self) -> Vec<PathBuf> {
+        let mut changed = HashSet::new();
+        loop {
+            let events = match self.inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EAGAIN) => break,
+                Err(e) => {
+                    eprintln!("Failed to read directory changes: {e}");
+                    break;
+                }
+            };
+            let mut watches = self.watches.borrow_mut();
+            for event in events {
+                if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                    // Events were lost, so anything could have changed
+                    changed.extend(watches.keys().cloned());
+                    continue;
+                }
+                changed.extend(watches.iter().filter(|(_, wd)| **wd == event.wd).map(|(dir, _)| dir.clone()));
+                if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    // The directory was removed, it is watched again if it comes back on a later scan
+                    watches.retain(|_, wd| *wd != event.wd);
+                    self.wanted.borrow_mut().clear();
+                }
+            }
+        }
+        changed.into_iter().collect()
+    }
+}
+
+impl AsFd for DirWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+}